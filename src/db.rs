@@ -1,38 +1,79 @@
-use rusqlite::{params, Connection};
-use std::sync::{Arc, Mutex};
+use rusqlite::{params, Connection, OptionalExtension};
+use r2d2_sqlite::SqliteConnectionManager;
+use std::sync::{Arc, Mutex, RwLock};
 use std::path::PathBuf;
 use anyhow::Result;
 
+pub type DbPool = r2d2::Pool<SqliteConnectionManager>;
+pub type DbConn = r2d2::PooledConnection<SqliteConnectionManager>;
+
 pub struct DbManager {
-    pub conn: Arc<Mutex<Connection>>,
+    pool: RwLock<DbPool>,
     current_file: Arc<Mutex<Option<PathBuf>>>,
 }
 
 impl DbManager {
     pub fn new() -> Self {
-        let conn = Connection::open_in_memory().expect("Failed to open memory DB");
         Self {
-            conn: Arc::new(Mutex::new(conn)),
+            pool: RwLock::new(Self::memory_pool().expect("Failed to open memory DB")),
             current_file: Arc::new(Mutex::new(None)),
         }
     }
 
-    fn get_storage_dir() -> PathBuf {
+    // Per-connection settings; foreign_keys in particular doesn't persist in the file
+    fn with_pragmas(manager: SqliteConnectionManager) -> SqliteConnectionManager {
+        manager.with_init(|c| {
+            c.busy_timeout(std::time::Duration::from_millis(5000))?;
+            c.execute_batch("PRAGMA foreign_keys = ON;")
+        })
+    }
+
+    // Every in-memory connection is its own database, so the fresh-start pool holds exactly
+    // one long-lived connection. File pools get real concurrency.
+    fn memory_pool() -> Result<DbPool> {
+        Ok(r2d2::Pool::builder()
+            .max_size(1)
+            .idle_timeout(None)
+            .max_lifetime(None)
+            .build(Self::with_pragmas(SqliteConnectionManager::memory()))?)
+    }
+
+    fn file_pool(path: &std::path::Path) -> Result<DbPool> {
+        Ok(r2d2::Pool::builder()
+            .max_size(8)
+            .build(Self::with_pragmas(SqliteConnectionManager::file(path)))?)
+    }
+
+    pub fn conn(&self) -> Result<DbConn> {
+        // A poisoned lock only means another request panicked mid-swap; the pool itself is fine
+        let pool = self.pool.read().unwrap_or_else(|e| e.into_inner()).clone();
+        Ok(pool.get()?)
+    }
+
+    pub fn get_storage_dir() -> PathBuf {
         std::env::current_exe()
             .map(|p| p.parent().unwrap().to_path_buf())
             .unwrap_or_else(|_| std::env::current_dir().unwrap())
     }
 
     pub fn init_schema(&self) -> Result<()> {
-        let conn = self.conn.lock().unwrap();
+        Self::init_schema_on(&*self.conn()?)
+    }
+
+    /// Creates missing tables, migrates and seeds the providers on `conn`, which may belong
+    /// to a pool that isn't live yet.
+    fn init_schema_on(conn: &Connection) -> Result<()> {
+
+        // WAL lets local_db_search read while we write; in-memory DBs just report "memory"
+        conn.pragma_update_and_check(None, "journal_mode", "WAL", |r| r.get::<_, String>(0))?;
         
         conn.execute_batch(
-            "PRAGMA foreign_keys = ON;
-            
-            CREATE TABLE IF NOT EXISTS conversations (
+            "CREATE TABLE IF NOT EXISTS conversations (
                 id INTEGER PRIMARY KEY AUTOINCREMENT,
                 title TEXT NOT NULL,
-                created_at DATETIME DEFAULT CURRENT_TIMESTAMP
+                created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+                is_archived BOOLEAN NOT NULL DEFAULT 0,
+                is_pinned BOOLEAN NOT NULL DEFAULT 0
             );
 
             CREATE TABLE IF NOT EXISTS messages (
@@ -53,6 +94,27 @@ impl DbManager {
                 FOREIGN KEY (conversation_id) REFERENCES conversations(id) ON DELETE CASCADE
             );
 
+            CREATE TABLE IF NOT EXISTS note_history (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                conversation_id INTEGER NOT NULL,
+                content TEXT NOT NULL,
+                created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+                FOREIGN KEY (conversation_id) REFERENCES conversations(id) ON DELETE CASCADE
+            );
+
+            CREATE TABLE IF NOT EXISTS tags (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                name TEXT NOT NULL UNIQUE COLLATE NOCASE
+            );
+
+            CREATE TABLE IF NOT EXISTS conversation_tags (
+                conversation_id INTEGER NOT NULL,
+                tag_id INTEGER NOT NULL,
+                PRIMARY KEY (conversation_id, tag_id),
+                FOREIGN KEY (conversation_id) REFERENCES conversations(id) ON DELETE CASCADE,
+                FOREIGN KEY (tag_id) REFERENCES tags(id) ON DELETE CASCADE
+            );
+
             CREATE TABLE IF NOT EXISTS search_providers (
                 id INTEGER PRIMARY KEY AUTOINCREMENT,
                 name TEXT NOT NULL,
@@ -63,7 +125,48 @@ impl DbManager {
                 title_path TEXT, 
                 url_path TEXT,
                 content_path TEXT,
-                is_enabled BOOLEAN DEFAULT 1
+                is_enabled BOOLEAN DEFAULT 1,
+                transform TEXT,
+                content_fallbacks TEXT,
+                date_path TEXT,
+                priority INTEGER NOT NULL DEFAULT 0,
+                timeout_ms INTEGER,
+                max_results INTEGER
+            );
+
+            CREATE TABLE IF NOT EXISTS query_log (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                query TEXT NOT NULL,
+                timeframe TEXT,
+                providers TEXT,
+                result_count INTEGER,
+                created_at DATETIME DEFAULT CURRENT_TIMESTAMP
+            );
+
+            CREATE TABLE IF NOT EXISTS prompt_presets (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                name TEXT NOT NULL UNIQUE COLLATE NOCASE,
+                content TEXT NOT NULL,
+                created_at DATETIME DEFAULT CURRENT_TIMESTAMP
+            );
+
+            CREATE TABLE IF NOT EXISTS bookmarks (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                url TEXT NOT NULL UNIQUE,
+                title TEXT NOT NULL,
+                note TEXT,
+                created_at DATETIME DEFAULT CURRENT_TIMESTAMP
+            );
+
+            CREATE TABLE IF NOT EXISTS pinned_sources (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                conversation_id INTEGER NOT NULL,
+                url TEXT NOT NULL,
+                title TEXT NOT NULL,
+                content TEXT NOT NULL DEFAULT '',
+                created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+                UNIQUE (conversation_id, url),
+                FOREIGN KEY (conversation_id) REFERENCES conversations(id) ON DELETE CASCADE
             );
 
             CREATE VIRTUAL TABLE IF NOT EXISTS messages_fts USING fts5(
@@ -73,9 +176,37 @@ impl DbManager {
             CREATE TRIGGER IF NOT EXISTS messages_after_insert AFTER INSERT ON messages BEGIN
                 INSERT INTO messages_fts(rowid, content) VALUES (new.id, new.content);
             END;
+
+            CREATE TRIGGER IF NOT EXISTS messages_after_delete AFTER DELETE ON messages BEGIN
+                INSERT INTO messages_fts(messages_fts, rowid, content) VALUES ('delete', old.id, old.content);
+            END;
+
+            CREATE TRIGGER IF NOT EXISTS messages_after_update AFTER UPDATE OF content ON messages BEGIN
+                INSERT INTO messages_fts(messages_fts, rowid, content) VALUES ('delete', old.id, old.content);
+                INSERT INTO messages_fts(rowid, content) VALUES (new.id, new.content);
+            END;
+
+            -- Every result snippet ever shown, searchable on its own
+            CREATE VIRTUAL TABLE IF NOT EXISTS sources_fts USING fts5(
+                url, title, content, message_id UNINDEXED
+            );
+
+            CREATE TRIGGER IF NOT EXISTS messages_sources_after_insert AFTER INSERT ON messages
+            WHEN new.sources IS NOT NULL AND json_valid(new.sources) BEGIN
+                INSERT INTO sources_fts(url, title, content, message_id)
+                SELECT json_extract(value, '$.url'), json_extract(value, '$.title'), json_extract(value, '$.content'), new.id
+                FROM json_each(new.sources) WHERE json_extract(value, '$.url') IS NOT NULL;
+            END;
+
+            CREATE TRIGGER IF NOT EXISTS messages_sources_after_delete AFTER DELETE ON messages BEGIN
+                DELETE FROM sources_fts WHERE message_id = old.id;
+            END;
             "
         )?;
 
+        // Older .db files predate columns added since; bring them up to SCHEMA_VERSION
+        run_migrations(conn)?;
+
         // Ensure defaults exist. 
         // Tuple: (Name, Type, API_URL, Enabled)
         // 1 = Checked by default, 0 = Unchecked
@@ -84,34 +215,57 @@ impl DbManager {
             ("DuckDuckGo", "native", "native_ddg", 0),
             ("Qwant", "native", "native_qwant", 0),
             ("Mojeek", "native", "native_mojeek", 0),
+            ("Ecosia", "native", "native_ecosia", 0),
             ("Wikipedia", "native", "native_wiki", 0),
             ("Reddit", "native", "native_reddit", 0),
             ("StackExchange", "native", "native_stack", 0),
+            ("GitHub", "native", "native_github", 0),
+            ("Internet Archive", "native", "native_wayback", 0),
+            ("Lemmy", "native", "native_lemmy", 0),
+            ("Semantic Scholar", "native", "native_semanticscholar", 0),
+            ("PubMed", "native", "native_pubmed", 0),
+            ("Google News", "native", "native_googlenews", 0),
         ];
-
-        if std::env::var("SEARXNG_URL").is_ok() {
-             let count: i64 = conn.query_row("SELECT count(*) FROM search_providers WHERE api_url = 'native_searxng'", [], |r| r.get(0)).unwrap_or(0);
-             if count == 0 {
-                 conn.execute("INSERT INTO search_providers (name, type, api_url, is_enabled) VALUES (?, ?, ?, 0)", 
-                   params!["SearXNG", "native", "native_searxng"]).unwrap();
-             }
-        }
-
-        for (name, ptype, url, enabled) in defaults {
-            let count: i64 = conn.query_row("SELECT count(*) FROM search_providers WHERE api_url = ?", params![url], |r| r.get(0)).unwrap_or(0);
-            if count == 0 {
-                conn.execute(
-                    "INSERT INTO search_providers (name, type, api_url, is_enabled) VALUES (?, ?, ?, ?)",
-                    params![name, ptype, url, enabled],
-                ).unwrap();
+        let mut seeds = Vec::new();
+        if let Some(list) = std::env::var("DEFAULT_PROVIDERS").ok().filter(|v| !v.trim().is_empty()) {
+            // The deployer's pick replaces the built-ins, each one checked
+            for id in list.split(',').map(str::trim).filter(|id| !id.is_empty()) {
+                let url = if id.starts_with("native_") { id.to_string() } else { format!("native_{}", id) };
+                match NATIVE_PROVIDERS.iter().find(|(_, u)| *u == url) {
+                    Some((name, url)) => seeds.push((*name, "native", *url, 1)),
+                    None => tracing::warn!("DEFAULT_PROVIDERS: unknown provider id '{}', ignoring", id),
+                }
+            }
+        } else {
+            // Only offered when configured, seeded ahead of the built-ins
+            if !crate::search::searxng_instances().is_empty() {
+                seeds.push(("SearXNG", "native", "native_searxng", 0));
+            }
+            if std::env::var("YOUTUBE_API_KEY").is_ok_and(|k| !k.is_empty()) {
+                seeds.push(("YouTube", "native", "native_youtube", 0));
             }
+            if std::env::var("MARGINALIA_API_KEY").is_ok_and(|k| !k.is_empty()) {
+                seeds.push(("Marginalia", "native", "native_marginalia", 0));
+            }
+            seeds.extend(defaults);
+        }
+
+        // Check-and-insert in one statement, one transaction, so concurrent inits can't both seed
+        let tx = conn.unchecked_transaction()?;
+        for (name, ptype, url, enabled) in seeds {
+            tx.execute(
+                "INSERT INTO search_providers (name, type, api_url, is_enabled)
+                 SELECT ?1, ?2, ?3, ?4 WHERE NOT EXISTS (SELECT 1 FROM search_providers WHERE api_url = ?3)",
+                params![name, ptype, url, enabled],
+            )?;
         }
+        tx.commit()?;
 
         Ok(())
     }
 
     pub fn add_message(&self, conv_id: i64, role: &str, content: &str, sources: Option<&str>) -> Result<i64> {
-        let conn = self.conn.lock().unwrap();
+        let conn = self.conn()?;
         conn.execute(
             "INSERT INTO messages (conversation_id, role, content, sources) VALUES (?, ?, ?, ?)",
             params![conv_id, role, content, sources],
@@ -119,8 +273,26 @@ impl DbManager {
         Ok(conn.last_insert_rowid())
     }
 
+    pub fn prompt_preset(&self, id: i64) -> Result<Option<String>> {
+        Ok(self.conn()?.query_row("SELECT content FROM prompt_presets WHERE id = ?", params![id], |r| r.get(0)).optional()?)
+    }
+
+    /// Sources pinned to a conversation, oldest first, ready to lead its result lists.
+    pub fn pinned_sources(&self, conv_id: i64) -> Result<Vec<crate::search::SearchResult>> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare("SELECT url, title, content FROM pinned_sources WHERE conversation_id = ? ORDER BY id")?;
+        let rows = stmt.query_map(params![conv_id], |r| Ok(crate::search::SearchResult {
+            url: r.get(0)?,
+            title: r.get(1)?,
+            content: r.get(2)?,
+            engine: "Pinned".into(),
+            ..Default::default()
+        }))?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
     pub fn get_history(&self, conv_id: i64) -> Result<Vec<crate::llm::Message>> {
-        let conn = self.conn.lock().unwrap();
+        let conn = self.conn()?;
         let mut stmt = conn.prepare("SELECT role, content FROM messages WHERE conversation_id = ? ORDER BY created_at ASC")?;
         let rows = stmt.query_map(params![conv_id], |row| {
             Ok(crate::llm::Message { role: row.get(0)?, content: row.get(1)? })
@@ -133,10 +305,79 @@ impl DbManager {
         Ok(history)
     }
 
+    /// `providers` is stored as a JSON array of ids, `None` meaning the default selection.
+    pub fn log_query(&self, query: &str, timeframe: Option<&str>, providers: Option<&[i64]>, result_count: usize) -> Result<()> {
+        let providers = providers.map(serde_json::to_string).transpose()?;
+        self.conn()?.execute(
+            "INSERT INTO query_log (query, timeframe, providers, result_count) VALUES (?, ?, ?, ?)",
+            params![query.trim(), timeframe.filter(|t| !t.is_empty()), providers, result_count as i64],
+        )?;
+        Ok(())
+    }
+
+    /// Distinct past queries starting with `prefix`, most recently used first.
+    pub fn recent_queries_matching(&self, prefix: &str, limit: usize) -> Result<Vec<String>> {
+        let conn = self.conn()?;
+        let pattern = format!("{}%", prefix.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_"));
+        let mut stmt = conn.prepare(
+            "SELECT query FROM query_log WHERE query LIKE ? ESCAPE '\\'
+             GROUP BY query COLLATE NOCASE ORDER BY MAX(id) DESC LIMIT ?"
+        )?;
+        let rows = stmt.query_map(params![pattern, limit as i64], |r| r.get(0))?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// Deletes every message, the note (with its history) and the pinned sources of a
+    /// conversation, keeping the conversation itself and its tags.
+    /// Returns the number of rows deleted, or `None` if the conversation doesn't exist.
+    pub fn clear_conversation(&self, conv_id: i64) -> Result<Option<usize>> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        let exists: bool = tx.query_row("SELECT EXISTS(SELECT 1 FROM conversations WHERE id = ?)", params![conv_id], |r| r.get(0))?;
+        if !exists { return Ok(None); }
+        let mut deleted = 0;
+        for table in ["messages", "notes", "note_history", "pinned_sources"] {
+            deleted += tx.execute(&format!("DELETE FROM {} WHERE conversation_id = ?", table), params![conv_id])?;
+        }
+        tx.commit()?;
+        Ok(Some(deleted))
+    }
+
+    /// Drops everything after the last user message (the answer being regenerated)
+    /// and returns that message's text, or `None` if nobody has asked anything yet.
+    pub fn rewind_to_last_user_message(&self, conv_id: i64) -> Result<Option<String>> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        let last: Option<(i64, String)> = tx.query_row(
+            "SELECT id, content FROM messages WHERE conversation_id = ? AND role = 'user' ORDER BY id DESC LIMIT 1",
+            params![conv_id], |r| Ok((r.get(0)?, r.get(1)?))
+        ).optional()?;
+        let Some((id, content)) = last else { return Ok(None) };
+        tx.execute("DELETE FROM messages WHERE conversation_id = ? AND id > ?", params![conv_id, id])?;
+        tx.commit()?;
+        Ok(Some(content))
+    }
+
+    /// The conversation's final message when it is an assistant answer: `(id, content, sources)`.
+    pub fn last_assistant_message(&self, conv_id: i64) -> Result<Option<(i64, String, Option<String>)>> {
+        let last = self.conn()?.query_row(
+            "SELECT id, role, content, sources FROM messages WHERE conversation_id = ? ORDER BY id DESC LIMIT 1",
+            params![conv_id], |r| Ok((r.get::<_, i64>(0)?, r.get::<_, String>(1)?, r.get(2)?, r.get(3)?))
+        ).optional()?;
+        Ok(last.filter(|(_, role, _, _)| role == "assistant").map(|(id, _, content, sources)| (id, content, sources)))
+    }
+
+    /// Appends `text` to a message, keeping the full-text index in step.
+    pub fn append_to_message(&self, id: i64, text: &str) -> Result<()> {
+        let conn = self.conn()?;
+        conn.execute("UPDATE messages SET content = content || ? WHERE id = ?", params![text, id])?;
+        Ok(())
+    }
+
     pub fn get_providers(&self, ids: Option<Vec<i64>>) -> Result<Vec<crate::search::ProviderConfig>> {
-        let conn = self.conn.lock().unwrap();
+        let conn = self.conn()?;
         // Added is_enabled to the query
-        let query = "SELECT id, name, type, api_url, api_headers, result_path, title_path, url_path, content_path, is_enabled FROM search_providers".to_string();
+        let query = "SELECT id, name, type, api_url, api_headers, result_path, title_path, url_path, content_path, is_enabled, transform, content_fallbacks, date_path, priority, timeout_ms, max_results FROM search_providers".to_string();
         let mut stmt = conn.prepare(&query)?;
         
         let iter = stmt.query_map([], |row| {
@@ -150,7 +391,15 @@ impl DbManager {
                 title_path: row.get(6)?,
                 url_path: row.get(7)?,
                 content_path: row.get(8)?,
-                is_enabled: row.get(9)?, // Map the bool
+                // Rows written by hand can leave the flag NULL; treat those as enabled
+                is_enabled: row.get::<_, Option<bool>>(9)?.unwrap_or(true),
+                transform: row.get(10)?,
+                content_fallbacks: row.get(11)?,
+                date_path: row.get(12)?,
+                priority: row.get(13)?,
+                timeout_ms: row.get(14)?,
+                max_results: row.get(15)?,
+                ..Default::default()
             })
         })?;
 
@@ -166,77 +415,854 @@ impl DbManager {
         Ok(providers)
     }
 
+    pub fn export_conversation(&self, conv_id: i64) -> Result<Option<crate::export::ExportedConversation>> {
+        let conn = self.conn()?;
+        let head: Option<(String, Option<String>)> = conn.query_row(
+            "SELECT title, created_at FROM conversations WHERE id = ?", params![conv_id], |r| Ok((r.get(0)?, r.get(1)?))
+        ).optional()?;
+        let Some((title, created_at)) = head else { return Ok(None) };
+
+        let mut stmt = conn.prepare("SELECT role, content, sources, created_at FROM messages WHERE conversation_id = ? ORDER BY created_at ASC, id ASC")?;
+        let rows = stmt.query_map(params![conv_id], |r| {
+            let sources: Option<String> = r.get(2)?;
+            Ok(crate::export::ExportedMessage {
+                role: r.get(0)?,
+                content: r.get(1)?,
+                sources: sources.and_then(|s| serde_json::from_str(&s).ok()).unwrap_or_default(),
+                created_at: r.get(3)?,
+            })
+        })?;
+        let mut messages = Vec::new();
+        for m in rows { messages.push(m?); }
+        let note: Option<String> = conn.query_row("SELECT content FROM notes WHERE conversation_id = ?", params![conv_id], |r| r.get(0)).optional()?;
+        Ok(Some(crate::export::ExportedConversation {
+            version: crate::export::EXPORT_VERSION,
+            id: Some(conv_id),
+            title, created_at, messages, note,
+        }))
+    }
+
+    /// Non-empty notes as `(conversation title, updated_at, content)`, oldest update first,
+    /// optionally only for conversations carrying `tag`.
+    pub fn notes_digest(&self, tag: Option<&str>) -> Result<Vec<(String, Option<String>, String)>> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare(
+            "SELECT c.title, n.updated_at, n.content FROM notes n JOIN conversations c ON c.id = n.conversation_id
+             WHERE trim(n.content) != ''
+             AND (?1 IS NULL OR EXISTS (
+                SELECT 1 FROM conversation_tags ct JOIN tags t ON t.id = ct.tag_id
+                WHERE ct.conversation_id = c.id AND t.name = ?1
+             ))
+             ORDER BY n.updated_at ASC, n.id ASC"
+        )?;
+        let rows = stmt.query_map(params![tag], |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)))?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    pub fn get_tags(&self, conv_id: i64) -> Result<Vec<Tag>> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare(
+            "SELECT t.id, t.name FROM tags t JOIN conversation_tags ct ON ct.tag_id = t.id
+             WHERE ct.conversation_id = ? ORDER BY t.name"
+        )?;
+        let rows = stmt.query_map(params![conv_id], |r| Ok(Tag { id: r.get(0)?, name: r.get(1)? }))?;
+        let mut tags = Vec::new();
+        for t in rows { tags.push(t?); }
+        Ok(tags)
+    }
+
+    /// Attaches a tag by name, creating it on first use.
+    pub fn add_tag(&self, conv_id: i64, name: &str) -> Result<Tag> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        tx.execute("INSERT INTO tags (name) VALUES (?) ON CONFLICT(name) DO NOTHING", params![name])?;
+        let tag = tx.query_row("SELECT id, name FROM tags WHERE name = ?", params![name], |r| Ok(Tag { id: r.get(0)?, name: r.get(1)? }))?;
+        tx.execute("INSERT OR IGNORE INTO conversation_tags (conversation_id, tag_id) VALUES (?, ?)", params![conv_id, tag.id])?;
+        tx.commit()?;
+        Ok(tag)
+    }
+
+    /// Upserts the note, first copying the previous version into `note_history` (last 20 kept).
+    pub fn save_note(&self, conv_id: i64, content: &str) -> Result<()> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        let previous: Option<String> = tx.query_row("SELECT content FROM notes WHERE conversation_id = ?", params![conv_id], |r| r.get(0)).optional()?;
+        if let Some(prev) = previous.filter(|p| p != content) {
+            tx.execute("INSERT INTO note_history (conversation_id, content) VALUES (?, ?)", params![conv_id, prev])?;
+            tx.execute(
+                "DELETE FROM note_history WHERE conversation_id = ?1 AND id NOT IN
+                    (SELECT id FROM note_history WHERE conversation_id = ?1 ORDER BY id DESC LIMIT 20)",
+                params![conv_id],
+            )?;
+        }
+        tx.execute(
+            "INSERT INTO notes (conversation_id, content) VALUES (?, ?)
+             ON CONFLICT(conversation_id) DO UPDATE SET content=excluded.content, updated_at=CURRENT_TIMESTAMP",
+            params![conv_id, content],
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Recreates an exported conversation under a new id. All-or-nothing.
+    pub fn import_conversation(&self, conv: &crate::export::ExportedConversation) -> Result<i64> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        tx.execute(
+            "INSERT INTO conversations (title, created_at) VALUES (?, COALESCE(?, CURRENT_TIMESTAMP))",
+            params![conv.title, conv.created_at],
+        )?;
+        let conv_id = tx.last_insert_rowid();
+        for m in &conv.messages {
+            let sources = if m.sources.is_empty() { None } else { Some(serde_json::to_string(&m.sources)?) };
+            tx.execute(
+                "INSERT INTO messages (conversation_id, role, content, sources, created_at) VALUES (?, ?, ?, ?, COALESCE(?, CURRENT_TIMESTAMP))",
+                params![conv_id, m.role, m.content, sources, m.created_at],
+            )?;
+        }
+        if let Some(note) = conv.note.as_deref().filter(|n| !n.is_empty()) {
+            tx.execute("INSERT INTO notes (conversation_id, content) VALUES (?, ?)", params![conv_id, note])?;
+        }
+        tx.commit()?;
+        Ok(conv_id)
+    }
+
     pub fn load_file(&self, filename: &str) -> Result<()> {
-        let path = Self::get_storage_dir().join(filename);
-        let new_conn = Connection::open(&path)?;
+        let path = Self::get_storage_dir().join(check_db_filename(filename)?);
+        if !path.is_file() {
+            return Err(DbFileError::NotFound(filename.to_string()).into());
+        }
+        self.load_path(path, filename)
+    }
+
+    fn load_path(&self, path: PathBuf, filename: &str) -> Result<()> {
+        // Probe first so a bad name or a non-SQLite file never replaces the live DB. Not read-only:
+        // quick_check runs the FTS5 integrity check, which needs a writable handle (it never creates the file)
+        let probe = Connection::open_with_flags(&path, rusqlite::OpenFlags::SQLITE_OPEN_READ_WRITE)
+            .map_err(|e| DbFileError::Invalid(format!("{}: {}", filename, e)))?;
+        let check: String = probe.query_row("PRAGMA quick_check", [], |r| r.get(0))
+            .map_err(|e| DbFileError::Invalid(format!("{}: {}", filename, e)))?;
+        if check != "ok" {
+            return Err(DbFileError::Invalid(format!("{}: integrity check failed ({})", filename, check)).into());
+        }
+        drop(probe);
+
+        // Migrate and seed before going live, a file that fails halfway must not be served
+        let new_pool = Self::file_pool(&path)?;
+        Self::init_schema_on(&*new_pool.get()?)?;
         {
-            let mut conn_guard = self.conn.lock().unwrap();
-            *conn_guard = new_conn;
-            let mut path_guard = self.current_file.lock().unwrap();
+            let mut pool_guard = self.pool.write().unwrap_or_else(|e| e.into_inner());
+            *pool_guard = new_pool;
+            let mut path_guard = self.current_file.lock().unwrap_or_else(|e| e.into_inner());
             *path_guard = Some(path);
         }
-        self.init_schema()?;
+        Ok(())
+    }
+
+    pub fn checkpoint(&self) -> Result<()> {
+        let conn = self.conn()?;
+        conn.execute_batch("PRAGMA wal_checkpoint(TRUNCATE);")?;
         Ok(())
     }
 
     pub fn save_to_file(&self, filename: &str) -> Result<()> {
-        let path = Self::get_storage_dir().join(filename);
-        let conn = self.conn.lock().unwrap();
+        let path = Self::get_storage_dir().join(check_db_filename(filename)?);
+        let conn = self.conn()?;
         conn.backup(rusqlite::DatabaseName::Main, &path, None)?;
         Ok(())
     }
+
+    /// File name of the loaded research database, `None` while running in memory.
+    pub fn current_file_name(&self) -> Option<String> {
+        self.current_file.lock().unwrap_or_else(|e| e.into_inner()).as_ref()
+            .and_then(|p| p.file_name()).map(|n| n.to_string_lossy().to_string())
+    }
+
+    /// Files the Local Database provider should search. With `LOCAL_DB_SCOPE=current` that is
+    /// only the loaded file (nothing while running in memory); otherwise `None`, every `.db`
+    /// in the working directory.
+    pub fn local_search_scope(&self) -> Option<Vec<PathBuf>> {
+        self.search_scope(std::env::var("LOCAL_DB_SCOPE").is_ok_and(|v| v.trim().eq_ignore_ascii_case("current")))
+    }
+
+    fn search_scope(&self, current_only: bool) -> Option<Vec<PathBuf>> {
+        current_only.then(|| self.current_file.lock().unwrap_or_else(|e| e.into_inner()).iter().cloned().collect())
+    }
+
+    /// `.db` files in the storage dir, sorted by name; empty if the dir can't be read.
+    pub fn list_files() -> Vec<String> {
+        let Ok(entries) = std::fs::read_dir(Self::get_storage_dir()) else { return vec![] };
+        let mut files: Vec<String> = entries.flatten()
+            .filter(|e| e.path().extension().is_some_and(|x| x == "db"))
+            .map(|e| e.file_name().to_string_lossy().to_string())
+            .collect();
+        files.sort();
+        files
+    }
+
+    /// Removes a saved `.db` file (and its WAL/SHM sidecars). The loaded file can't be deleted.
+    pub fn delete_file(&self, filename: &str) -> Result<()> {
+        let name = check_db_filename(filename)?;
+        if !name.ends_with(".db") {
+            return Err(DbFileError::Invalid(format!("'{}' is not a .db file", filename)).into());
+        }
+        let path = Self::get_storage_dir().join(name);
+        if !path.is_file() {
+            return Err(DbFileError::NotFound(filename.to_string()).into());
+        }
+        if self.current_file.lock().unwrap_or_else(|e| e.into_inner()).as_deref() == Some(path.as_path()) {
+            return Err(DbFileError::InUse(filename.to_string()).into());
+        }
+        std::fs::remove_file(&path)?;
+        for suffix in ["-wal", "-shm"] {
+            let _ = std::fs::remove_file(path.with_file_name(format!("{}{}", name, suffix)));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum DbFileError {
+    NotFound(String),
+    Invalid(String),
+    InUse(String),
+}
+
+impl std::fmt::Display for DbFileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DbFileError::NotFound(name) => write!(f, "Database file not found: {}", name),
+            DbFileError::Invalid(msg) => write!(f, "Not a valid database file: {}", msg),
+            DbFileError::InUse(name) => write!(f, "Database file is currently loaded: {}", name),
+        }
+    }
+}
+
+impl std::error::Error for DbFileError {}
+
+/// Research files live flat in the storage dir; reject anything that could point elsewhere.
+pub fn check_db_filename(filename: &str) -> Result<&str> {
+    if filename.is_empty() || filename.contains(['/', '\\']) || filename.contains("..") {
+        return Err(DbFileError::Invalid(format!("invalid file name '{}'", filename)).into());
+    }
+    Ok(filename)
+}
+
+/// Native providers `DEFAULT_PROVIDERS` can name, as `(display name, api_url)`.
+const NATIVE_PROVIDERS: &[(&str, &str)] = &[
+    ("Local Database", "native_local_db"),
+    ("DuckDuckGo", "native_ddg"),
+    ("Qwant", "native_qwant"),
+    ("Mojeek", "native_mojeek"),
+    ("Ecosia", "native_ecosia"),
+    ("Wikipedia", "native_wiki"),
+    ("Reddit", "native_reddit"),
+    ("StackExchange", "native_stack"),
+    ("GitHub", "native_github"),
+    ("YouTube", "native_youtube"),
+    ("Marginalia", "native_marginalia"),
+    ("Internet Archive", "native_wayback"),
+    ("Lemmy", "native_lemmy"),
+    ("Semantic Scholar", "native_semanticscholar"),
+    ("PubMed", "native_pubmed"),
+    ("Google News", "native_googlenews"),
+    ("SearXNG", "native_searxng"),
+];
+
+// --- Migrations ---
+//
+// `init_schema` creates fresh databases with every column already present, so each step
+// must be idempotent: it only fills in what an older file is missing. Step N leaves the
+// database at `PRAGMA user_version = N`. Append new steps, never reorder them.
+
+type Migration = fn(&Connection) -> Result<()>;
+
+const MIGRATIONS: &[Migration] = &[
+    // 1: generic provider transforms
+    |c| ensure_column(c, "search_providers", "transform", "TEXT"),
+    // 2: generic provider content fallbacks
+    |c| ensure_column(c, "search_providers", "content_fallbacks", "TEXT"),
+    // 3: conversation archive/pin flags
+    |c| {
+        ensure_column(c, "conversations", "is_archived", "BOOLEAN NOT NULL DEFAULT 0")?;
+        ensure_column(c, "conversations", "is_pinned", "BOOLEAN NOT NULL DEFAULT 0")
+    },
+    // 4: generic provider publication dates
+    |c| ensure_column(c, "search_providers", "date_path", "TEXT"),
+    // 5: provider priority
+    |c| ensure_column(c, "search_providers", "priority", "INTEGER NOT NULL DEFAULT 0"),
+    // 6: query log details
+    |c| {
+        ensure_column(c, "query_log", "timeframe", "TEXT")?;
+        ensure_column(c, "query_log", "providers", "TEXT")?;
+        ensure_column(c, "query_log", "result_count", "INTEGER")
+    },
+    // 7: one row per native provider (earlier racing inits could seed duplicates)
+    |c| {
+        c.execute_batch(
+            "DELETE FROM search_providers WHERE type = 'native' AND id NOT IN
+                (SELECT MIN(id) FROM search_providers WHERE type = 'native' GROUP BY api_url);
+             CREATE UNIQUE INDEX IF NOT EXISTS idx_search_providers_native_url
+                ON search_providers(api_url) WHERE type = 'native';"
+        )?;
+        Ok(())
+    },
+    // 8: index sources stored before sources_fts existed
+    |c| {
+        c.execute_batch(
+            "DELETE FROM sources_fts;
+             INSERT INTO sources_fts(url, title, content, message_id)
+             SELECT json_extract(j.value, '$.url'), json_extract(j.value, '$.title'), json_extract(j.value, '$.content'), m.id
+             FROM messages m, json_each(m.sources) j
+             WHERE m.sources IS NOT NULL AND json_valid(m.sources) AND json_extract(j.value, '$.url') IS NOT NULL;"
+        )?;
+        Ok(())
+    },
+    // 9: drop index entries left behind by deleted messages (no delete trigger before this)
+    |c| {
+        c.execute_batch("INSERT INTO messages_fts(messages_fts) VALUES ('rebuild');")?;
+        Ok(())
+    },
+    // 10: per-provider time budget and result cap
+    |c| {
+        ensure_column(c, "search_providers", "timeout_ms", "INTEGER")?;
+        ensure_column(c, "search_providers", "max_results", "INTEGER")
+    },
+];
+
+pub const SCHEMA_VERSION: usize = MIGRATIONS.len();
+
+pub fn run_migrations(conn: &Connection) -> Result<()> {
+    let version: usize = conn.query_row("PRAGMA user_version", [], |r| r.get(0))?;
+    if version < SCHEMA_VERSION {
+        tracing::info!("Migrating database schema from v{} to v{}", version, SCHEMA_VERSION);
+    }
+    for (i, step) in MIGRATIONS.iter().enumerate().skip(version) {
+        let tx = conn.unchecked_transaction()?;
+        step(&tx)?;
+        tx.pragma_update(None, "user_version", (i + 1) as i64)?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+fn ensure_column(conn: &Connection, table: &str, column: &str, decl: &str) -> Result<()> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", table))?;
+    let exists = stmt.query_map([], |r| r.get::<_, String>(1))?.flatten().any(|c| c == column);
+    if !exists {
+        conn.execute_batch(&format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, decl))?;
+    }
+    Ok(())
+}
+
+#[derive(serde::Serialize, Clone, Debug)]
+pub struct Tag {
+    pub id: i64,
+    pub name: String,
 }
 
 pub mod routes {
     use super::*;
-    use axum::{Json, extract::{Path, State}, http::StatusCode};
+    use axum::{Json, extract::{Path, Query, State}, http::{header, StatusCode}, response::{IntoResponse, Response}};
+    use futures::StreamExt;
     use serde::{Deserialize, Serialize};
 
+    /// Error returned by route handlers, rendered as `{"error": ...}` with a status code.
+    /// Anything convertible to `anyhow::Error` turns into one, so handlers can use `?`.
+    #[derive(Debug)]
+    pub struct ApiError(StatusCode, String);
+
+    impl ApiError {
+        pub fn bad_request(msg: impl Into<String>) -> Self { Self(StatusCode::BAD_REQUEST, msg.into()) }
+        pub fn not_found(msg: impl Into<String>) -> Self { Self(StatusCode::NOT_FOUND, msg.into()) }
+        pub fn conflict(msg: impl Into<String>) -> Self { Self(StatusCode::CONFLICT, msg.into()) }
+    }
+
+    impl<E: Into<anyhow::Error>> From<E> for ApiError {
+        fn from(e: E) -> Self {
+            let e = e.into();
+            let status = match e.downcast_ref::<DbFileError>() {
+                Some(DbFileError::NotFound(_)) => StatusCode::NOT_FOUND,
+                Some(DbFileError::Invalid(_)) => StatusCode::BAD_REQUEST,
+                Some(DbFileError::InUse(_)) => StatusCode::CONFLICT,
+                // Constraint failures come from bad input (e.g. a tag on a missing conversation)
+                None => match e.downcast_ref::<rusqlite::Error>() {
+                    Some(rusqlite::Error::SqliteFailure(f, _)) if f.code == rusqlite::ErrorCode::ConstraintViolation => StatusCode::BAD_REQUEST,
+                    _ => StatusCode::INTERNAL_SERVER_ERROR,
+                },
+            };
+            if status.is_server_error() { tracing::error!("request failed: {:#}", e); }
+            Self(status, e.to_string())
+        }
+    }
+
+    impl IntoResponse for ApiError {
+        fn into_response(self) -> Response {
+            (self.0, Json(serde_json::json!({ "error": self.1 }))).into_response()
+        }
+    }
+
+    type ApiResult<T> = Result<T, ApiError>;
+
     #[derive(Serialize)]
-    pub struct Conversation { id: i64, title: String, created_at: String }
-    pub async fn list_conversations(State(state): State<Arc<crate::AppState>>) -> Json<Vec<Conversation>> {
-        let conn = state.db.conn.lock().unwrap();
-        let mut stmt = conn.prepare("SELECT id, title, created_at FROM conversations ORDER BY created_at DESC").unwrap();
-        let rows = stmt.query_map([], |r| Ok(Conversation{id:r.get(0)?, title:r.get(1)?, created_at:r.get(2)?})).unwrap();
-        Json(rows.map(|r| r.unwrap()).collect())
+    pub struct Conversation { id: i64, title: String, created_at: String, is_archived: bool, is_pinned: bool, tags: Vec<Tag> }
+
+    #[derive(Deserialize)]
+    pub struct ListParams { tag: Option<String>, include_archived: Option<bool> }
+
+    pub async fn list_conversations(Query(params): Query<ListParams>, State(state): State<Arc<crate::AppState>>) -> ApiResult<Json<Vec<Conversation>>> {
+        let mut convs: Vec<Conversation> = {
+            let conn = state.db.conn()?;
+            let mut stmt = conn.prepare(
+                "SELECT c.id, c.title, c.created_at, c.is_archived, c.is_pinned FROM conversations c
+                 WHERE (?1 IS NULL OR EXISTS (
+                    SELECT 1 FROM conversation_tags ct JOIN tags t ON t.id = ct.tag_id
+                    WHERE ct.conversation_id = c.id AND t.name = ?1
+                 ))
+                 AND (?2 OR c.is_archived = 0)
+                 ORDER BY c.is_pinned DESC, c.created_at DESC"
+            )?;
+            let rows = stmt.query_map(params![params.tag, params.include_archived.unwrap_or(false)], |r| Ok(Conversation{
+                id:r.get(0)?, title:r.get(1)?, created_at:r.get(2)?, is_archived:r.get(3)?, is_pinned:r.get(4)?, tags: vec![]
+            }))?;
+            rows.collect::<rusqlite::Result<_>>()?
+        };
+        for c in convs.iter_mut() { c.tags = state.db.get_tags(c.id)?; }
+        Ok(Json(convs))
+    }
+
+    #[derive(Deserialize)]
+    pub struct FlagsReq { is_archived: Option<bool>, is_pinned: Option<bool> }
+
+    pub async fn set_flags(Path(id): Path<i64>, State(state): State<Arc<crate::AppState>>, Json(req): Json<FlagsReq>) -> ApiResult<StatusCode> {
+        let changed = state.db.conn()?.execute(
+            "UPDATE conversations SET is_archived = COALESCE(?, is_archived), is_pinned = COALESCE(?, is_pinned) WHERE id = ?",
+            params![req.is_archived, req.is_pinned, id]
+        )?;
+        if changed == 0 { return Err(ApiError::not_found("Conversation not found")); }
+        Ok(StatusCode::NO_CONTENT)
+    }
+
+    #[derive(Deserialize)]
+    pub struct TagReq { name: String }
+
+    pub async fn add_tag(Path(id): Path<i64>, State(state): State<Arc<crate::AppState>>, Json(req): Json<TagReq>) -> ApiResult<Json<Tag>> {
+        let name = req.name.trim();
+        if name.is_empty() { return Err(ApiError::bad_request("Tag name is required")); }
+        Ok(Json(state.db.add_tag(id, name)?))
+    }
+
+    pub async fn remove_tag(Path((id, tag_id)): Path<(i64, i64)>, State(state): State<Arc<crate::AppState>>) -> ApiResult<StatusCode> {
+        state.db.conn()?.execute("DELETE FROM conversation_tags WHERE conversation_id = ? AND tag_id = ?", params![id, tag_id])?;
+        Ok(StatusCode::NO_CONTENT)
     }
     
     #[derive(Deserialize)] 
     pub struct CreateConv { title: Option<String> }
     
-    pub async fn create_conversation(State(state): State<Arc<crate::AppState>>, Json(req): Json<CreateConv>) -> Json<serde_json::Value> {
-        let conn = state.db.conn.lock().unwrap();
-        conn.execute("INSERT INTO conversations (title) VALUES (?)", params![req.title.unwrap_or("New Chat".into())]).unwrap();
-        Json(serde_json::json!({ "id": conn.last_insert_rowid() }))
+    pub async fn create_conversation(State(state): State<Arc<crate::AppState>>, Json(req): Json<CreateConv>) -> ApiResult<Json<serde_json::Value>> {
+        let conn = state.db.conn()?;
+        conn.execute("INSERT INTO conversations (title) VALUES (?)", params![req.title.unwrap_or("New Chat".into())])?;
+        Ok(Json(serde_json::json!({ "id": conn.last_insert_rowid() })))
+    }
+
+    #[derive(Deserialize)]
+    pub struct GetConvParams { sources: Option<String> }
+
+    /// `?sources=summary` replaces each message's sources with a `source_count`; the full
+    /// list is then fetched per message from `/messages/:mid/sources`.
+    pub async fn get_conversation(Path(id): Path<i64>, Query(params): Query<GetConvParams>, State(state): State<Arc<crate::AppState>>) -> ApiResult<Json<serde_json::Value>> {
+        let summary = match params.sources.as_deref() {
+            None | Some("full") => false,
+            Some("summary") => true,
+            Some(other) => return Err(ApiError::bad_request(format!("Unsupported sources mode '{}'", other))),
+        };
+        let conn = state.db.conn()?;
+        let msgs: Vec<serde_json::Value> = if summary {
+            let mut stmt = conn.prepare("SELECT role, content, COALESCE(json_array_length(sources), 0), id FROM messages WHERE conversation_id = ? ORDER BY created_at ASC")?;
+            let rows = stmt.query_map(params![id], |r| {
+                Ok(serde_json::json!({ "id": r.get::<_,i64>(3)?, "role": r.get::<_,String>(0)?, "content": r.get::<_,String>(1)?, "source_count": r.get::<_,i64>(2)? }))
+            })?;
+            rows.collect::<rusqlite::Result<_>>()?
+        } else {
+            let mut stmt = conn.prepare("SELECT role, content, sources, id FROM messages WHERE conversation_id = ? ORDER BY created_at ASC")?;
+            let rows = stmt.query_map(params![id], |r| {
+                Ok(serde_json::json!({ "id": r.get::<_,i64>(3)?, "role": r.get::<_,String>(0)?, "content": r.get::<_,String>(1)?, "sources": r.get::<_,Option<String>>(2)? }))
+            })?;
+            rows.collect::<rusqlite::Result<_>>()?
+        };
+        let note: Option<String> = conn.query_row("SELECT content FROM notes WHERE conversation_id = ?", params![id], |r| r.get(0)).optional()?;
+        drop(conn);
+        let tags = state.db.get_tags(id)?;
+        Ok(Json(serde_json::json!({ "messages": msgs, "note_content": note, "tags": tags })))
+    }
+
+    pub async fn delete_conversation(Path(id): Path<i64>, State(state): State<Arc<crate::AppState>>) -> ApiResult<StatusCode> {
+        state.db.conn()?.execute("DELETE FROM conversations WHERE id = ?", params![id])?;
+        Ok(StatusCode::NO_CONTENT)
+    }
+
+    pub async fn clear_conversation(Path(id): Path<i64>, State(state): State<Arc<crate::AppState>>) -> ApiResult<Json<serde_json::Value>> {
+        let deleted = state.db.clear_conversation(id)?.ok_or_else(|| ApiError::not_found("Conversation not found"))?;
+        Ok(Json(serde_json::json!({ "deleted": deleted })))
+    }
+
+    #[derive(Deserialize)]
+    pub struct ExportParams { format: Option<String> }
+
+    pub async fn export_conversation(Path(id): Path<i64>, Query(params): Query<ExportParams>, State(state): State<Arc<crate::AppState>>) -> ApiResult<Response> {
+        let conv = state.db.export_conversation(id)?.ok_or_else(|| ApiError::not_found("Conversation not found"))?;
+        let stem = crate::export::file_stem(&conv.title);
+        Ok(match params.format.as_deref().unwrap_or("md") {
+            "md" | "markdown" => (
+                [
+                    (header::CONTENT_TYPE, "text/markdown; charset=utf-8".to_string()),
+                    (header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}.md\"", stem)),
+                ],
+                crate::export::conversation_markdown(&conv),
+            ).into_response(),
+            "json" => (
+                [(header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}.json\"", stem))],
+                Json(conv),
+            ).into_response(),
+            other => return Err(ApiError::bad_request(format!("Unsupported export format '{}'", other))),
+        })
+    }
+
+    #[derive(Deserialize)]
+    pub struct NotesExportParams { tag: Option<String> }
+
+    /// Every conversation's note in one Markdown document.
+    pub async fn export_notes(Query(params): Query<NotesExportParams>, State(state): State<Arc<crate::AppState>>) -> ApiResult<Response> {
+        let notes = state.db.notes_digest(params.tag.as_deref().filter(|t| !t.is_empty()))?;
+        Ok((
+            [
+                (header::CONTENT_TYPE, "text/markdown; charset=utf-8"),
+                (header::CONTENT_DISPOSITION, "attachment; filename=\"notes.md\""),
+            ],
+            crate::export::notes_markdown(&notes),
+        ).into_response())
+    }
+
+    pub async fn import_conversation(State(state): State<Arc<crate::AppState>>, Json(payload): Json<serde_json::Value>) -> ApiResult<(StatusCode, Json<serde_json::Value>)> {
+        if !payload["messages"].is_array() {
+            return Err(ApiError::bad_request("Payload must contain a messages array"));
+        }
+        let conv: crate::export::ExportedConversation = serde_json::from_value(payload)
+            .map_err(|e| ApiError::bad_request(format!("Invalid conversation payload: {}", e)))?;
+        let id = state.db.import_conversation(&conv)?;
+        Ok((StatusCode::CREATED, Json(serde_json::json!({ "id": id }))))
+    }
+
+    #[derive(Deserialize)]
+    pub struct SourceSearchParams { q: String, limit: Option<usize> }
+
+    /// Links surfaced in any past answer whose url, title or snippet matches `q`, best match
+    /// first and one entry per url (from its most recent message).
+    pub async fn search_sources(Query(params): Query<SourceSearchParams>, State(state): State<Arc<crate::AppState>>) -> ApiResult<Json<Vec<serde_json::Value>>> {
+        // Each word as a quoted phrase, so user input can't trip FTS query syntax
+        let fts_query = params.q.split_whitespace()
+            .map(|w| format!("\"{}\"", w.replace('"', "")))
+            .collect::<Vec<_>>().join(" ");
+        if fts_query.is_empty() {
+            return Err(ApiError::bad_request("q must not be empty"));
+        }
+        let limit = params.limit.unwrap_or(20).clamp(1, 100);
+        let conn = state.db.conn()?;
+        let mut stmt = conn.prepare(
+            "SELECT s.url, s.title, s.content, m.id, m.conversation_id, m.created_at
+             FROM sources_fts s JOIN messages m ON m.id = s.message_id
+             WHERE sources_fts MATCH ? ORDER BY s.rank, m.id DESC LIMIT ?"
+        )?;
+        let rows = stmt.query_map(params![fts_query, (limit * 5) as i64], |r| Ok(serde_json::json!({
+            "url": r.get::<_, String>(0)?,
+            "title": r.get::<_, Option<String>>(1)?,
+            "content": r.get::<_, Option<String>>(2)?,
+            "message_id": r.get::<_, i64>(3)?,
+            "conversation_id": r.get::<_, i64>(4)?,
+            "created_at": r.get::<_, Option<String>>(5)?,
+        })))?;
+        let mut seen = std::collections::HashSet::new();
+        let mut hits = Vec::new();
+        for hit in rows {
+            let hit = hit?;
+            if seen.insert(hit["url"].as_str().unwrap_or_default().to_string()) { hits.push(hit); }
+            if hits.len() == limit { break; }
+        }
+        Ok(Json(hits))
+    }
+
+    #[derive(Deserialize)]
+    pub struct CitationParams { style: Option<String> }
+
+    /// A message's stored sources; 404 unless the message belongs to the conversation.
+    fn load_message_sources(state: &crate::AppState, id: i64, mid: i64) -> ApiResult<Vec<crate::search::SearchResult>> {
+        let raw: Option<String> = state.db.conn()?.query_row(
+            "SELECT sources FROM messages WHERE id = ? AND conversation_id = ?",
+            params![mid, id],
+            |r| r.get(0),
+        ).optional()?.ok_or_else(|| ApiError::not_found("Message not found"))?;
+        Ok(raw.as_deref().and_then(|s| serde_json::from_str(s).ok()).unwrap_or_default())
+    }
+
+    pub async fn message_sources(Path((id, mid)): Path<(i64, i64)>, State(state): State<Arc<crate::AppState>>) -> ApiResult<Json<Vec<crate::search::SearchResult>>> {
+        Ok(Json(load_message_sources(&state, id, mid)?))
+    }
+
+    pub async fn citations(Path((id, mid)): Path<(i64, i64)>, Query(params): Query<CitationParams>, State(state): State<Arc<crate::AppState>>) -> ApiResult<impl IntoResponse> {
+        let sources = load_message_sources(&state, id, mid)?;
+        let body = crate::export::citations(&sources, params.style.as_deref().unwrap_or("numbered"), chrono::Local::now().date_naive())
+            .map_err(|e| ApiError::bad_request(e.to_string()))?;
+        Ok(([(header::CONTENT_TYPE, "text/plain; charset=utf-8")], body))
+    }
+
+    #[derive(Deserialize)]
+    pub struct BibliographyParams { format: Option<String>, fetch_titles: Option<bool> }
+
+    pub async fn bibliography(Path(id): Path<i64>, Query(params): Query<BibliographyParams>, State(state): State<Arc<crate::AppState>>) -> ApiResult<impl IntoResponse> {
+        let source_rows: Vec<String> = {
+            let conn = state.db.conn()?;
+            let mut stmt = conn.prepare("SELECT sources FROM messages WHERE conversation_id = ? AND role = 'assistant' AND sources IS NOT NULL ORDER BY created_at ASC")?;
+            let rows = stmt.query_map(params![id], |r| r.get::<_, String>(0))?;
+            rows.collect::<rusqlite::Result<_>>()?
+        };
+
+        let mut sources = bibliography_sources(&source_rows);
+
+        if params.fetch_titles.unwrap_or(false) {
+            let client = crate::search::page_client()?;
+            let lookups: Vec<(bool, String)> = sources.iter()
+                .map(|s| (s.title.trim().is_empty() || s.title == "No Title", s.url.clone()))
+                .collect();
+            let fetches = lookups.into_iter().map(|(missing, url)| {
+                let client = client.clone();
+                async move { if missing { fetch_page_title(&client, &url).await } else { None } }
+            });
+            let titles: Vec<Option<String>> = futures::stream::iter(fetches).buffered(TITLE_FETCH_CONCURRENCY).collect().await;
+            for (s, t) in sources.iter_mut().zip(titles) {
+                if let Some(t) = t { s.title = t; }
+            }
+        }
+
+        let accessed = chrono::Local::now().date_naive();
+        let (body, content_type) = match params.format.as_deref() {
+            Some("bibtex") => (crate::export::bibliography_bibtex(&sources, accessed), "application/x-bibtex; charset=utf-8"),
+            _ => (crate::export::bibliography_markdown(&sources, accessed), "text/markdown; charset=utf-8"),
+        };
+        Ok(([(header::CONTENT_TYPE, content_type)], body))
+    }
+
+    /// Web sources across every assistant message's `sources` JSON, deduplicated by
+    /// normalized URL in first-seen order.
+    fn bibliography_sources(source_rows: &[String]) -> Vec<crate::search::SearchResult> {
+        let mut seen = std::collections::HashSet::new();
+        let mut sources = Vec::new();
+        for raw in source_rows {
+            for s in serde_json::from_str::<Vec<crate::search::SearchResult>>(raw).unwrap_or_default() {
+                if s.url.is_empty() || s.url.starts_with("local://") { continue; }
+                if seen.insert(normalize_url(&s.url)) { sources.push(s); }
+            }
+        }
+        sources
     }
 
-    pub async fn get_conversation(Path(id): Path<i64>, State(state): State<Arc<crate::AppState>>) -> Json<serde_json::Value> {
-        let conn = state.db.conn.lock().unwrap();
-        let mut stmt = conn.prepare("SELECT role, content, sources FROM messages WHERE conversation_id = ? ORDER BY created_at ASC").unwrap();
-        let msgs: Vec<serde_json::Value> = stmt.query_map(params![id], |r| {
-            Ok(serde_json::json!({ "role": r.get::<_,String>(0)?, "content": r.get::<_,String>(1)?, "sources": r.get::<_,Option<String>>(2)? }))
-        }).unwrap().map(|r| r.unwrap()).collect();
-        let note: Option<String> = conn.query_row("SELECT content FROM notes WHERE conversation_id = ?", params![id], |r| r.get(0)).ok();
-        Json(serde_json::json!({ "messages": msgs, "note_content": note }))
+    /// Lowercases scheme/host, drops `www.`, fragments and trailing slashes so
+    /// trivially different links to the same page collapse together.
+    fn normalize_url(raw: &str) -> String {
+        match reqwest::Url::parse(raw) {
+            Ok(mut u) => {
+                u.set_fragment(None);
+                let host = u.host_str().unwrap_or("").trim_start_matches("www.").to_string();
+                let path = u.path().trim_end_matches('/').to_string();
+                let query = u.query().map(|q| format!("?{}", q)).unwrap_or_default();
+                format!("{}://{}{}{}", u.scheme(), host, path, query)
+            },
+            Err(_) => raw.trim().trim_end_matches('/').to_lowercase(),
+        }
     }
 
-    pub async fn delete_conversation(Path(id): Path<i64>, State(state): State<Arc<crate::AppState>>) -> StatusCode {
-        state.db.conn.lock().unwrap().execute("DELETE FROM conversations WHERE id = ?", params![id]).unwrap();
-        StatusCode::NO_CONTENT
+    /// Title lookups running at once for one bibliography.
+    const TITLE_FETCH_CONCURRENCY: usize = 4;
+    /// The `<title>` sits in the head, no need to read further than this.
+    const TITLE_FETCH_MAX_BYTES: usize = 256 * 1024;
+
+    /// `<title>` of a source page, fetched with the same host checks and byte cap as deep reads.
+    async fn fetch_page_title(client: &reqwest::Client, url: &str) -> Option<String> {
+        let html = crate::search::fetch_page(client, url, TITLE_FETCH_MAX_BYTES).await?;
+        let doc = scraper::Html::parse_document(&html);
+        let sel = scraper::Selector::parse("title").unwrap();
+        let title = doc.select(&sel).next()?.text().collect::<String>().trim().to_string();
+        if title.is_empty() { None } else { Some(title) }
     }
 
     #[derive(Deserialize)] 
     pub struct NoteReq { content: String }
-    pub async fn save_note(Path(id): Path<i64>, State(state): State<Arc<crate::AppState>>, Json(req): Json<NoteReq>) -> Json<serde_json::Value> {
-        state.db.conn.lock().unwrap().execute("INSERT INTO notes (conversation_id, content) VALUES (?, ?) ON CONFLICT(conversation_id) DO UPDATE SET content=excluded.content", params![id, req.content]).unwrap();
-        Json(serde_json::json!({"status": "ok"}))
+    pub async fn save_note(Path(id): Path<i64>, State(state): State<Arc<crate::AppState>>, Json(req): Json<NoteReq>) -> ApiResult<Json<serde_json::Value>> {
+        state.db.save_note(id, &req.content)?;
+        Ok(Json(serde_json::json!({"status": "ok"})))
+    }
+
+    #[derive(Serialize)]
+    pub struct NoteVersion { id: i64, content: String, created_at: String }
+    pub async fn note_history(Path(id): Path<i64>, State(state): State<Arc<crate::AppState>>) -> ApiResult<Json<Vec<NoteVersion>>> {
+        let conn = state.db.conn()?;
+        let mut stmt = conn.prepare("SELECT id, content, created_at FROM note_history WHERE conversation_id = ? ORDER BY id DESC")?;
+        let rows = stmt.query_map(params![id], |r| Ok(NoteVersion{id:r.get(0)?, content:r.get(1)?, created_at:r.get(2)?}))?;
+        Ok(Json(rows.collect::<rusqlite::Result<_>>()?))
+    }
+
+    // --- Prompt Preset Routes ---
+
+    #[derive(Serialize)]
+    pub struct PromptPreset { id: i64, name: String, content: String, created_at: String }
+
+    #[derive(Deserialize)]
+    pub struct PromptPresetReq { name: String, content: String }
+
+    pub async fn list_prompts(State(state): State<Arc<crate::AppState>>) -> ApiResult<Json<Vec<PromptPreset>>> {
+        let conn = state.db.conn()?;
+        let mut stmt = conn.prepare("SELECT id, name, content, created_at FROM prompt_presets ORDER BY name COLLATE NOCASE")?;
+        let rows = stmt.query_map([], |r| Ok(PromptPreset { id: r.get(0)?, name: r.get(1)?, content: r.get(2)?, created_at: r.get(3)? }))?;
+        Ok(Json(rows.collect::<rusqlite::Result<_>>()?))
+    }
+
+    pub async fn add_prompt(State(state): State<Arc<crate::AppState>>, Json(req): Json<PromptPresetReq>) -> ApiResult<(StatusCode, Json<serde_json::Value>)> {
+        let name = req.name.trim();
+        if name.is_empty() || req.content.trim().is_empty() {
+            return Err(ApiError::bad_request("Preset name and content are required"));
+        }
+        let conn = state.db.conn()?;
+        let exists: bool = conn.query_row("SELECT EXISTS(SELECT 1 FROM prompt_presets WHERE name = ?)", params![name], |r| r.get(0))?;
+        if exists { return Err(ApiError::conflict(format!("A preset named '{}' already exists", name))); }
+        conn.execute("INSERT INTO prompt_presets (name, content) VALUES (?, ?)", params![name, req.content])?;
+        Ok((StatusCode::CREATED, Json(serde_json::json!({ "id": conn.last_insert_rowid() }))))
+    }
+
+    pub async fn delete_prompt(Path(id): Path<i64>, State(state): State<Arc<crate::AppState>>) -> ApiResult<StatusCode> {
+        let deleted = state.db.conn()?.execute("DELETE FROM prompt_presets WHERE id = ?", params![id])?;
+        if deleted == 0 { return Err(ApiError::not_found("Preset not found")); }
+        Ok(StatusCode::NO_CONTENT)
+    }
+
+    // --- Bookmark Routes ---
+
+    /// A `SearchResult` (only `url` is required) plus an optional note.
+    #[derive(Deserialize)]
+    pub struct BookmarkReq {
+        url: String,
+        #[serde(default)]
+        title: String,
+        note: Option<String>,
+    }
+
+    #[derive(Serialize)]
+    pub struct Bookmark { id: i64, url: String, title: String, note: Option<String>, created_at: String }
+
+    /// Saving a url that is already bookmarked returns the existing bookmark's id with 200.
+    pub async fn add_bookmark(State(state): State<Arc<crate::AppState>>, Json(req): Json<BookmarkReq>) -> ApiResult<(StatusCode, Json<serde_json::Value>)> {
+        let url = req.url.trim();
+        if url.is_empty() { return Err(ApiError::bad_request("Bookmark url is required")); }
+        let title = if req.title.trim().is_empty() { url } else { req.title.trim() };
+        let conn = state.db.conn()?;
+        let inserted = conn.execute(
+            "INSERT INTO bookmarks (url, title, note) VALUES (?, ?, ?) ON CONFLICT(url) DO NOTHING",
+            params![url, title, req.note.as_deref().filter(|n| !n.trim().is_empty())],
+        )?;
+        let id: i64 = conn.query_row("SELECT id FROM bookmarks WHERE url = ?", params![url], |r| r.get(0))?;
+        let status = if inserted > 0 { StatusCode::CREATED } else { StatusCode::OK };
+        Ok((status, Json(serde_json::json!({ "id": id }))))
+    }
+
+    pub async fn list_bookmarks(State(state): State<Arc<crate::AppState>>) -> ApiResult<Json<Vec<Bookmark>>> {
+        let conn = state.db.conn()?;
+        let mut stmt = conn.prepare("SELECT id, url, title, note, created_at FROM bookmarks ORDER BY id DESC")?;
+        let rows = stmt.query_map([], |r| Ok(Bookmark {
+            id: r.get(0)?, url: r.get(1)?, title: r.get(2)?, note: r.get(3)?, created_at: r.get(4)?
+        }))?;
+        Ok(Json(rows.collect::<rusqlite::Result<_>>()?))
+    }
+
+    pub async fn delete_bookmark(Path(id): Path<i64>, State(state): State<Arc<crate::AppState>>) -> ApiResult<StatusCode> {
+        let deleted = state.db.conn()?.execute("DELETE FROM bookmarks WHERE id = ?", params![id])?;
+        if deleted == 0 { return Err(ApiError::not_found("Bookmark not found")); }
+        Ok(StatusCode::NO_CONTENT)
+    }
+
+    #[derive(Deserialize)]
+    pub struct PinReq {
+        url: String,
+        #[serde(default)]
+        title: String,
+        #[serde(default)]
+        content: String,
+    }
+
+    #[derive(Serialize)]
+    pub struct PinnedSource { id: i64, url: String, title: String, content: String, created_at: String }
+
+    /// Pinning a url the conversation already has returns the existing pin's id with 200.
+    pub async fn add_pinned_source(Path(id): Path<i64>, State(state): State<Arc<crate::AppState>>, Json(req): Json<PinReq>) -> ApiResult<(StatusCode, Json<serde_json::Value>)> {
+        let url = req.url.trim();
+        if url.is_empty() { return Err(ApiError::bad_request("Pinned source url is required")); }
+        let title = if req.title.trim().is_empty() { url } else { req.title.trim() };
+        let conn = state.db.conn()?;
+        let exists: bool = conn.query_row("SELECT EXISTS(SELECT 1 FROM conversations WHERE id = ?)", params![id], |r| r.get(0))?;
+        if !exists { return Err(ApiError::not_found("Conversation not found")); }
+        let inserted = conn.execute(
+            "INSERT INTO pinned_sources (conversation_id, url, title, content) VALUES (?, ?, ?, ?)
+             ON CONFLICT(conversation_id, url) DO NOTHING",
+            params![id, url, title, req.content.trim()],
+        )?;
+        let pin_id: i64 = conn.query_row(
+            "SELECT id FROM pinned_sources WHERE conversation_id = ? AND url = ?", params![id, url], |r| r.get(0)
+        )?;
+        let status = if inserted > 0 { StatusCode::CREATED } else { StatusCode::OK };
+        Ok((status, Json(serde_json::json!({ "id": pin_id }))))
+    }
+
+    pub async fn list_pinned_sources(Path(id): Path<i64>, State(state): State<Arc<crate::AppState>>) -> ApiResult<Json<Vec<PinnedSource>>> {
+        let conn = state.db.conn()?;
+        let mut stmt = conn.prepare("SELECT id, url, title, content, created_at FROM pinned_sources WHERE conversation_id = ? ORDER BY id")?;
+        let rows = stmt.query_map(params![id], |r| Ok(PinnedSource {
+            id: r.get(0)?, url: r.get(1)?, title: r.get(2)?, content: r.get(3)?, created_at: r.get(4)?
+        }))?;
+        Ok(Json(rows.collect::<rusqlite::Result<_>>()?))
+    }
+
+    pub async fn delete_pinned_source(Path((id, pin_id)): Path<(i64, i64)>, State(state): State<Arc<crate::AppState>>) -> ApiResult<StatusCode> {
+        let deleted = state.db.conn()?.execute(
+            "DELETE FROM pinned_sources WHERE id = ? AND conversation_id = ?", params![pin_id, id]
+        )?;
+        if deleted == 0 { return Err(ApiError::not_found("Pinned source not found")); }
+        Ok(StatusCode::NO_CONTENT)
     }
 
     // --- Provider Routes ---
 
-    pub async fn list_providers(State(state): State<Arc<crate::AppState>>) -> Json<Vec<crate::search::ProviderConfig>> {
-        let providers = state.db.get_providers(None).unwrap_or_default();
-        Json(providers)
+    #[derive(Deserialize)]
+    pub struct RecentParams { limit: Option<usize> }
+
+    #[derive(Serialize)]
+    pub struct RecentQuery { query: String, last_used: String, uses: i64, result_count: Option<i64> }
+
+    /// Last N distinct queries, most recent first (default 20, max 200).
+    pub async fn recent_queries(Query(params): Query<RecentParams>, State(state): State<Arc<crate::AppState>>) -> ApiResult<Json<Vec<RecentQuery>>> {
+        let limit = params.limit.unwrap_or(20).clamp(1, 200);
+        let conn = state.db.conn()?;
+        let mut stmt = conn.prepare(
+            // With a single MAX() SQLite takes the bare columns from that row, i.e. the latest run
+            "SELECT query, created_at, COUNT(*), result_count, MAX(id) AS last_id
+             FROM query_log GROUP BY query COLLATE NOCASE ORDER BY last_id DESC LIMIT ?"
+        )?;
+        let rows = stmt.query_map(params![limit as i64], |r| Ok(RecentQuery {
+            query: r.get(0)?, last_used: r.get(1)?, uses: r.get(2)?, result_count: r.get(3)?
+        }))?;
+        Ok(Json(rows.collect::<rusqlite::Result<_>>()?))
+    }
+
+    pub async fn list_providers(State(state): State<Arc<crate::AppState>>) -> ApiResult<Json<Vec<crate::search::ProviderConfig>>> {
+        let providers = state.db.get_providers(None)?;
+        Ok(Json(providers.into_iter().map(crate::search::ProviderConfig::redacted).collect()))
     }
 
     #[derive(Deserialize)]
@@ -247,41 +1273,348 @@ pub mod routes {
         result_path: String,
         title_path: String,
         url_path: String,
-        content_path: String
+        content_path: String,
+        #[serde(default)]
+        transform: Option<String>,
+        #[serde(default)]
+        content_fallbacks: Option<String>,
+        #[serde(default)]
+        date_path: Option<String>,
+        #[serde(default)]
+        priority: i64,
+        #[serde(default)]
+        timeout_ms: Option<i64>,
+        #[serde(default)]
+        max_results: Option<i64>,
+        /// Echoed from a `list_providers` entry; such a config has masked secrets
+        #[serde(default)]
+        redacted: bool,
     }
 
-    pub async fn add_provider(State(state): State<Arc<crate::AppState>>, Json(req): Json<AddProviderReq>) -> Json<serde_json::Value> {
-        let conn = state.db.conn.lock().unwrap();
+    pub async fn add_provider(State(state): State<Arc<crate::AppState>>, Json(req): Json<AddProviderReq>) -> ApiResult<Json<serde_json::Value>> {
+        if req.redacted || crate::search::has_masked_headers(&req.api_headers) {
+            return Err(ApiError::bad_request("api_headers contain redacted (***) values, send the real ones"));
+        }
+        let transform = req.transform.filter(|t| !t.trim().is_empty());
+        if let Some(t) = &transform {
+            crate::transform::Transform::parse(t).map_err(|e| ApiError::bad_request(format!("Invalid transform: {}", e)))?;
+        }
+        let conn = state.db.conn()?;
         conn.execute(
-            "INSERT INTO search_providers (name, type, api_url, api_headers, result_path, title_path, url_path, content_path, is_enabled) 
-             VALUES (?, 'generic', ?, ?, ?, ?, ?, ?, 1)",
-            params![req.name, req.api_url, req.api_headers, req.result_path, req.title_path, req.url_path, req.content_path]
-        ).unwrap();
-        Json(serde_json::json!({ "id": conn.last_insert_rowid() }))
+            "INSERT INTO search_providers (name, type, api_url, api_headers, result_path, title_path, url_path, content_path, is_enabled, transform, content_fallbacks, date_path, priority, timeout_ms, max_results) 
+             VALUES (?, 'generic', ?, ?, ?, ?, ?, ?, 1, ?, ?, ?, ?, ?, ?)",
+            params![req.name, req.api_url, req.api_headers, req.result_path, req.title_path, req.url_path, req.content_path, transform, req.content_fallbacks.filter(|f| !f.trim().is_empty()), req.date_path.filter(|d| !d.trim().is_empty()), req.priority, positive(req.timeout_ms), positive(req.max_results)]
+        )?;
+        Ok(Json(serde_json::json!({ "id": conn.last_insert_rowid() })))
     }
 
-    pub async fn delete_provider(Path(id): Path<i64>, State(state): State<Arc<crate::AppState>>) -> StatusCode {
-        let conn = state.db.conn.lock().unwrap();
-        conn.execute("DELETE FROM search_providers WHERE id = ?", params![id]).unwrap();
-        StatusCode::NO_CONTENT
+    #[derive(Deserialize)]
+    pub struct UpdateProviderReq {
+        #[serde(flatten)]
+        fields: AddProviderReq,
+        #[serde(default)]
+        is_enabled: Option<bool>,
+    }
+
+    /// `PUT /api/providers/:id`: replaces a generic provider's definition in place, keeping its id.
+    /// Header values still redacted (`***`) from `list_providers` keep what is stored, so unlike
+    /// `add_provider` this accepts a config with `redacted` set.
+    pub async fn update_provider(Path(id): Path<i64>, State(state): State<Arc<crate::AppState>>, Json(req): Json<UpdateProviderReq>) -> ApiResult<StatusCode> {
+        let UpdateProviderReq { fields: req, is_enabled } = req;
+        let transform = req.transform.filter(|t| !t.trim().is_empty());
+        if let Some(t) = &transform {
+            crate::transform::Transform::parse(t).map_err(|e| ApiError::bad_request(format!("Invalid transform: {}", e)))?;
+        }
+        let conn = state.db.conn()?;
+        let stored: Option<Option<String>> = conn.query_row(
+            "SELECT api_headers FROM search_providers WHERE id = ? AND type = 'generic'", params![id], |r| r.get(0)
+        ).optional()?;
+        let Some(stored) = stored else { return Err(ApiError::not_found("Provider not found")); };
+        let api_headers = unredact_headers(&req.api_headers, stored.as_deref());
+        // A masked value with nothing stored under that header can't be restored
+        if crate::search::has_masked_headers(&api_headers) {
+            return Err(ApiError::bad_request("api_headers contain redacted (***) values that aren't stored, send the real ones"));
+        }
+        conn.execute(
+            "UPDATE search_providers SET name = ?, api_url = ?, api_headers = ?, result_path = ?, title_path = ?, url_path = ?, content_path = ?,
+                transform = ?, content_fallbacks = ?, date_path = ?, priority = ?, timeout_ms = ?, max_results = ?, is_enabled = COALESCE(?, is_enabled)
+             WHERE id = ?",
+            params![req.name, req.api_url, api_headers, req.result_path, req.title_path, req.url_path, req.content_path, transform, req.content_fallbacks.filter(|f| !f.trim().is_empty()), req.date_path.filter(|d| !d.trim().is_empty()), req.priority, positive(req.timeout_ms), positive(req.max_results), is_enabled, id]
+        )?;
+        Ok(StatusCode::NO_CONTENT)
+    }
+
+    /// Puts stored secrets back into headers a client echoed from a redacted listing.
+    fn unredact_headers(incoming: &str, stored: Option<&str>) -> String {
+        let is_masked = crate::search::is_masked_value;
+        if is_masked(incoming.trim()) {
+            return stored.unwrap_or_default().to_string();
+        }
+        let (Ok(mut headers), Some(Ok(stored))) = (
+            serde_json::from_str::<std::collections::HashMap<String, String>>(incoming),
+            stored.map(serde_json::from_str::<std::collections::HashMap<String, String>>),
+        ) else {
+            return incoming.to_string();
+        };
+        for (k, v) in headers.iter_mut() {
+            if is_masked(v) {
+                if let Some(original) = stored.get(k) { *v = original.clone(); }
+            }
+        }
+        serde_json::to_string(&headers).unwrap_or_else(|_| incoming.to_string())
+    }
+
+    /// Overrides only mean something when positive; zero or less clears them.
+    fn positive(v: Option<i64>) -> Option<i64> {
+        v.filter(|n| *n > 0)
+    }
+
+    /// Fields left out stay as they are; `timeout_ms`/`max_results` of 0 reset to the default.
+    #[derive(Deserialize)]
+    pub struct PatchProviderReq { priority: Option<i64>, timeout_ms: Option<i64>, max_results: Option<i64> }
+
+    pub async fn patch_provider(Path(id): Path<i64>, State(state): State<Arc<crate::AppState>>, Json(req): Json<PatchProviderReq>) -> ApiResult<StatusCode> {
+        let changed = state.db.conn()?.execute(
+            "UPDATE search_providers SET priority = COALESCE(?, priority),
+                timeout_ms = CASE WHEN ? IS NULL THEN timeout_ms ELSE ? END,
+                max_results = CASE WHEN ? IS NULL THEN max_results ELSE ? END
+             WHERE id = ?",
+            params![req.priority, req.timeout_ms, positive(req.timeout_ms), req.max_results, positive(req.max_results), id]
+        )?;
+        if changed == 0 { return Err(ApiError::not_found("Provider not found")); }
+        Ok(StatusCode::NO_CONTENT)
+    }
+
+    #[derive(Deserialize)]
+    pub struct SetEnabledReq { enabled: bool }
+
+    /// `PATCH /api/providers/:id/enabled`: switches a provider off (or back on) without losing its config.
+    pub async fn set_provider_enabled(Path(id): Path<i64>, State(state): State<Arc<crate::AppState>>, Json(req): Json<SetEnabledReq>) -> ApiResult<StatusCode> {
+        let changed = state.db.conn()?.execute(
+            "UPDATE search_providers SET is_enabled = ? WHERE id = ?",
+            params![req.enabled, id]
+        )?;
+        if changed == 0 { return Err(ApiError::not_found("Provider not found")); }
+        Ok(StatusCode::NO_CONTENT)
+    }
+
+    pub async fn delete_provider(Path(id): Path<i64>, State(state): State<Arc<crate::AppState>>) -> ApiResult<StatusCode> {
+        let deleted = state.db.conn()?.execute("DELETE FROM search_providers WHERE id = ?", params![id])?;
+        if deleted == 0 { return Err(ApiError::not_found("Provider not found")); }
+        Ok(StatusCode::NO_CONTENT)
     }
 
     #[derive(Deserialize)] 
     pub struct FileReq { filename: String }
-    pub async fn save_db(State(state): State<Arc<crate::AppState>>, Json(req): Json<FileReq>) -> Json<serde_json::Value> {
+    pub async fn save_db(State(state): State<Arc<crate::AppState>>, Json(req): Json<FileReq>) -> ApiResult<Json<serde_json::Value>> {
         let mut f = req.filename; if !f.ends_with(".db") { f.push_str(".db"); }
-        state.db.save_to_file(&f).unwrap();
-        Json(serde_json::json!({"message": "saved"}))
+        state.db.save_to_file(&f)?;
+        Ok(Json(serde_json::json!({"message": "saved"})))
     }
-    pub async fn load_db(State(state): State<Arc<crate::AppState>>, Json(req): Json<FileReq>) -> Json<serde_json::Value> {
-        state.db.load_file(&req.filename).unwrap();
-        Json(serde_json::json!({"message": "loaded"}))
+    pub async fn load_db(State(state): State<Arc<crate::AppState>>, Json(req): Json<FileReq>) -> ApiResult<Json<serde_json::Value>> {
+        state.db.load_file(&req.filename)?;
+        Ok(Json(serde_json::json!({"message": "loaded"})))
     }
     pub async fn list_db_files() -> Json<Vec<String>> {
-        let dir = DbManager::get_storage_dir();
-        let files = std::fs::read_dir(dir).unwrap().flatten()
-            .filter(|e| e.path().extension().map_or(false, |x| x=="db"))
-            .map(|e| e.file_name().to_string_lossy().to_string()).collect();
-        Json(files)
+        Json(DbManager::list_files())
+    }
+
+    pub async fn current_db(State(state): State<Arc<crate::AppState>>) -> Json<serde_json::Value> {
+        let filename = state.db.current_file_name();
+        Json(serde_json::json!({"filename": filename, "in_memory": filename.is_none()}))
+    }
+
+    /// Deletes a saved research file and returns the files that remain.
+    pub async fn delete_db_file(Path(filename): Path<String>, State(state): State<Arc<crate::AppState>>) -> ApiResult<Json<Vec<String>>> {
+        state.db.delete_file(&filename)?;
+        Ok(Json(DbManager::list_files()))
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn bibliography_dedups_sources_shared_across_messages() {
+            let rows = vec![
+                serde_json::json!([
+                    {"title": "Rust", "url": "https://www.rust-lang.org/", "content": "", "engine": "DDG"},
+                    {"title": "Notes", "url": "local://research.db/notes/x", "content": "", "engine": "LocalDB"},
+                ]).to_string(),
+                serde_json::json!([
+                    {"title": "Rust again", "url": "https://rust-lang.org#install", "content": "", "engine": "Qwant"},
+                    {"title": "Book", "url": "https://doc.rust-lang.org/book/", "content": "", "engine": "DDG"},
+                ]).to_string(),
+                "not json".to_string(),
+            ];
+            let sources = bibliography_sources(&rows);
+            let titles: Vec<_> = sources.iter().map(|s| s.title.as_str()).collect();
+            assert_eq!(titles, ["Rust", "Book"]);
+        }
+
+        #[tokio::test]
+        async fn title_lookups_skip_local_hosts() {
+            let addr = crate::testutil::serve(axum::Router::new().route("/", axum::routing::get(|| async {
+                axum::response::Html("<html><head><title>Admin panel</title></head></html>")
+            }))).await;
+            let client = crate::search::page_client().unwrap();
+            assert_eq!(fetch_page_title(&client, &format!("http://{}/", addr)).await, None);
+            assert_eq!(fetch_page_title(&client, "http://169.254.169.254/latest/meta-data/").await, None);
+        }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A manager backed by a fresh file in the temp dir, as if loaded from the storage dir.
+    fn file_db(name: &str) -> (DbManager, PathBuf) {
+        let path = std::env::temp_dir().join(format!("bplus-db-{}-{}.db", name, std::process::id()));
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+        let db = DbManager {
+            pool: RwLock::new(DbManager::file_pool(&path).unwrap()),
+            current_file: Arc::new(Mutex::new(Some(path.clone()))),
+        };
+        (db, path)
+    }
+
+    fn remove_db(path: PathBuf) {
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+    }
+
+    #[test]
+    fn file_connections_get_wal_and_busy_timeout() {
+        let (db, path) = file_db("pragmas");
+        db.init_schema().unwrap();
+        let conn = db.conn().unwrap();
+        let journal: String = conn.query_row("PRAGMA journal_mode", [], |r| r.get(0)).unwrap();
+        assert_eq!(journal, "wal");
+        let busy: i64 = conn.query_row("PRAGMA busy_timeout", [], |r| r.get(0)).unwrap();
+        assert_eq!(busy, 5000);
+        let fks: i64 = conn.query_row("PRAGMA foreign_keys", [], |r| r.get(0)).unwrap();
+        assert_eq!(fks, 1);
+        drop(conn);
+        remove_db(path);
+    }
+
+    #[test]
+    fn v0_database_migrates_to_current_schema() {
+        let db = DbManager::new();
+        db.conn().unwrap().execute_batch("
+            CREATE TABLE conversations (id INTEGER PRIMARY KEY AUTOINCREMENT, title TEXT NOT NULL, created_at DATETIME DEFAULT CURRENT_TIMESTAMP);
+            CREATE TABLE messages (id INTEGER PRIMARY KEY AUTOINCREMENT, conversation_id INTEGER NOT NULL, role TEXT NOT NULL, content TEXT NOT NULL, sources TEXT, created_at DATETIME DEFAULT CURRENT_TIMESTAMP);
+            CREATE TABLE search_providers (id INTEGER PRIMARY KEY AUTOINCREMENT, name TEXT NOT NULL, type TEXT NOT NULL, api_url TEXT, api_headers TEXT, result_path TEXT, title_path TEXT, url_path TEXT, content_path TEXT, is_enabled BOOLEAN DEFAULT 1);
+            CREATE TABLE query_log (id INTEGER PRIMARY KEY AUTOINCREMENT, query TEXT NOT NULL, created_at DATETIME DEFAULT CURRENT_TIMESTAMP);
+            INSERT INTO conversations (title) VALUES ('old chat');
+            INSERT INTO messages (conversation_id, role, content) VALUES (1, 'user', 'kept across the upgrade');
+        ").unwrap();
+
+        db.init_schema().unwrap();
+
+        let conn = db.conn().unwrap();
+        let version: usize = conn.query_row("PRAGMA user_version", [], |r| r.get(0)).unwrap();
+        assert_eq!(version, SCHEMA_VERSION);
+        let columns = |table: &str| -> Vec<String> {
+            let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", table)).unwrap();
+            stmt.query_map([], |r| r.get(1)).unwrap().flatten().collect()
+        };
+        for (table, wanted) in [
+            ("search_providers", &["transform", "content_fallbacks", "date_path", "priority", "timeout_ms", "max_results"][..]),
+            ("conversations", &["is_archived", "is_pinned"][..]),
+            ("query_log", &["timeframe", "providers", "result_count"][..]),
+        ] {
+            let have = columns(table);
+            for column in wanted {
+                assert!(have.iter().any(|c| c == column), "{}.{} missing after migration", table, column);
+            }
+        }
+        let hits: i64 = conn.query_row("SELECT count(*) FROM messages_fts WHERE messages_fts MATCH 'upgrade'", [], |r| r.get(0)).unwrap();
+        assert_eq!(hits, 1);
+    }
+
+    #[test]
+    fn init_schema_twice_seeds_each_provider_once() {
+        let db = DbManager::new();
+        db.init_schema().unwrap();
+        db.init_schema().unwrap();
+        let conn = db.conn().unwrap();
+        let ddg: i64 = conn.query_row("SELECT count(*) FROM search_providers WHERE api_url = 'native_ddg'", [], |r| r.get(0)).unwrap();
+        assert_eq!(ddg, 1);
+        let dupes: i64 = conn.query_row(
+            "SELECT count(*) FROM (SELECT api_url FROM search_providers GROUP BY api_url HAVING count(*) > 1)", [], |r| r.get(0),
+        ).unwrap();
+        assert_eq!(dupes, 0);
+    }
+
+    #[test]
+    fn scope_is_the_loaded_file_only_when_asked() {
+        let (db, path) = file_db("scope");
+        assert_eq!(db.search_scope(true), Some(vec![path.clone()]));
+        assert_eq!(db.search_scope(false), None);
+        assert_eq!(DbManager::new().search_scope(true), Some(vec![]), "in memory there is nothing to search");
+        remove_db(path);
+    }
+
+    #[test]
+    fn export_tells_missing_rows_from_errors() {
+        let db = DbManager::new();
+        db.init_schema().unwrap();
+        assert!(db.export_conversation(42).unwrap().is_none());
+        db.conn().unwrap().execute("INSERT INTO conversations (id, title) VALUES (1, 'No note')", []).unwrap();
+        assert_eq!(db.export_conversation(1).unwrap().unwrap().note, None);
+
+        db.conn().unwrap().execute_batch("DROP TABLE notes").unwrap();
+        assert!(db.export_conversation(1).is_err(), "a broken notes table must not read as 'no note'");
+    }
+
+    #[test]
+    fn clearing_a_conversation_drops_note_history_and_pins() {
+        let db = DbManager::new();
+        db.init_schema().unwrap();
+        db.conn().unwrap().execute_batch("
+            INSERT INTO conversations (id, title) VALUES (1, 'Cleared'), (2, 'Untouched');
+            INSERT INTO pinned_sources (conversation_id, url, title) VALUES (1, 'https://a', 'A'), (2, 'https://b', 'B');
+        ").unwrap();
+        for conv in [1, 2] {
+            db.add_message(conv, "user", "question", None).unwrap();
+            db.save_note(conv, "first").unwrap();
+            db.save_note(conv, "second").unwrap();
+        }
+
+        assert_eq!(db.clear_conversation(1).unwrap(), Some(4));
+        let conn = db.conn().unwrap();
+        for table in ["messages", "notes", "note_history", "pinned_sources"] {
+            let counts: (i64, i64) = conn.query_row(
+                &format!("SELECT sum(conversation_id = 1), sum(conversation_id = 2) FROM {}", table), [], |r| Ok((r.get(0)?, r.get(1)?)),
+            ).unwrap();
+            assert_eq!(counts, (0, 1), "{}", table);
+        }
+        drop(conn);
+        assert_eq!(db.clear_conversation(99).unwrap(), None);
+    }
+
+    #[test]
+    fn failed_load_keeps_serving_the_previous_db() {
+        let db = DbManager::new();
+        db.init_schema().unwrap();
+        db.conn().unwrap().execute("INSERT INTO conversations (title) VALUES ('still here')", []).unwrap();
+
+        // Passes the integrity check, but the FTS triggers can't be created on a view
+        let path = std::env::temp_dir().join(format!("bplus-db-broken-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        Connection::open(&path).unwrap().execute_batch(
+            "CREATE VIEW messages AS SELECT 1 AS id, 1 AS conversation_id, 'user' AS role, 'x' AS content, NULL AS sources, NULL AS created_at;"
+        ).unwrap();
+
+        assert!(db.load_path(path.clone(), "broken.db").is_err());
+        assert_eq!(db.current_file_name(), None);
+        let title: String = db.conn().unwrap().query_row("SELECT title FROM conversations", [], |r| r.get(0)).unwrap();
+        assert_eq!(title, "still here");
+        remove_db(path);
+    }
+}
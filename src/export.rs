@@ -0,0 +1,293 @@
+use crate::search::SearchResult;
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::path::{Component, Path, PathBuf};
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ExportedMessage {
+    pub role: String,
+    pub content: String,
+    #[serde(default)]
+    pub sources: Vec<SearchResult>,
+    pub created_at: Option<String>,
+}
+
+/// Bumped whenever the JSON export shape changes incompatibly.
+pub const EXPORT_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ExportedConversation {
+    #[serde(default)]
+    pub version: u32,
+    #[serde(default)]
+    pub id: Option<i64>,
+    pub title: String,
+    pub created_at: Option<String>,
+    pub messages: Vec<ExportedMessage>,
+    pub note: Option<String>,
+}
+
+pub fn sources_markdown(sources: &[SearchResult]) -> String {
+    sources.iter()
+        .map(|s| format!("- [{}]({})", s.title, s.url))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+pub fn conversation_markdown(conv: &ExportedConversation) -> String {
+    let mut out = format!("# {}\n", conv.title);
+    for m in &conv.messages {
+        let role = if m.role == "assistant" { "Assistant" } else { "User" };
+        out.push_str(&format!("\n## {}\n\n{}\n", role, m.content.trim_end()));
+        if !m.sources.is_empty() {
+            out.push_str(&format!("\n**Sources**\n\n{}\n", sources_markdown(&m.sources)));
+        }
+    }
+    if let Some(note) = conv.note.as_deref().filter(|n| !n.trim().is_empty()) {
+        out.push_str(&format!("\n## Notes\n\n{}\n", note.trim_end()));
+    }
+    out
+}
+
+/// Research digest from `(title, updated_at, content)` notes, one section per conversation.
+pub fn notes_markdown(notes: &[(String, Option<String>, String)]) -> String {
+    let mut out = String::from("# Research Notes\n");
+    for (title, updated_at, content) in notes {
+        out.push_str(&format!("\n## {}\n", title));
+        if let Some(updated_at) = updated_at {
+            out.push_str(&format!("\n_Updated {}_\n", updated_at));
+        }
+        out.push_str(&format!("\n{}\n", content.trim_end()));
+    }
+    out
+}
+
+/// Formats sources as a citation list: `numbered` (default), `apa` or `mla`.
+/// APA/MLA are best-effort, the only fields available are title, url and sometimes a date.
+pub fn citations(sources: &[SearchResult], style: &str, accessed: chrono::NaiveDate) -> Result<String> {
+    if !["numbered", "apa", "mla"].contains(&style) {
+        return Err(anyhow!("Unsupported citation style '{}'", style));
+    }
+    let lines: Vec<String> = sources.iter().enumerate().map(|(i, s)| {
+        let site = reqwest::Url::parse(&s.url).ok()
+            .and_then(|u| u.host_str().map(|h| h.trim_start_matches("www.").to_string()))
+            .unwrap_or_else(|| s.engine.clone());
+        let title = s.title.trim().trim_end_matches('.');
+        let published = s.date.as_deref().and_then(crate::search::parse_date).map(|d| d.date_naive());
+        match style {
+            "apa" => format!(
+                "{}. ({}). {}. Retrieved {}, from {}",
+                title,
+                published.map(|d| d.format("%Y, %B %-d").to_string()).unwrap_or_else(|| "n.d.".into()),
+                site,
+                accessed.format("%B %-d, %Y"),
+                s.url
+            ),
+            "mla" => format!(
+                "\"{}.\" {}, {}{}. Accessed {}.",
+                title,
+                site,
+                published.map(|d| format!("{}, ", d.format("%-d %b. %Y"))).unwrap_or_default(),
+                s.url,
+                accessed.format("%-d %b. %Y")
+            ),
+            _ => format!("[{}] {}. {}. {} (accessed {})", i + 1, title, site, s.url, accessed.format("%Y-%m-%d")),
+        }
+    }).collect();
+    Ok(lines.join("\n"))
+}
+
+/// Numbered Markdown bibliography. Titles have `[`/`]` escaped and URLs go in `<...>`,
+/// so neither can break out of the link.
+pub fn bibliography_markdown(sources: &[SearchResult], accessed: chrono::NaiveDate) -> String {
+    let lines: Vec<String> = sources.iter().enumerate().map(|(i, s)| {
+        let title = s.title.replace('\\', "\\\\").replace('[', "\\[").replace(']', "\\]");
+        let url = s.url.replace('<', "%3C").replace('>', "%3E");
+        format!("{}. [{}](<{}>) (accessed {})", i + 1, title, url, accessed.format("%Y-%m-%d"))
+    }).collect();
+    format!("# Bibliography\n\n{}\n", lines.join("\n"))
+}
+
+/// One `@misc` entry per source, with LaTeX special characters in titles escaped.
+pub fn bibliography_bibtex(sources: &[SearchResult], accessed: chrono::NaiveDate) -> String {
+    let entries: Vec<String> = sources.iter().enumerate().map(|(i, s)| format!(
+        "@misc{{ref{},\n  title = {{{}}},\n  howpublished = {{\\url{{{}}}}},\n  note = {{Accessed: {}}}\n}}",
+        i + 1,
+        bibtex_escape(&s.title),
+        // Unbalanced braces or a backslash would end \url early
+        s.url.replace('{', "%7B").replace('}', "%7D").replace('\\', "%5C"),
+        accessed.format("%Y-%m-%d")
+    )).collect();
+    entries.join("\n\n")
+}
+
+fn bibtex_escape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\\' => out.push_str("\\textbackslash{}"),
+            '{' | '}' => {},
+            '%' | '&' | '#' | '_' | '$' => { out.push('\\'); out.push(c); },
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+/// Makes a title safe to use as a download file name.
+pub fn file_stem(title: &str) -> String {
+    let stem: String = title.chars()
+        .map(|c| if c.is_alphanumeric() || c == ' ' || c == '-' || c == '_' { c } else { '_' })
+        .collect();
+    let stem = stem.trim();
+    if stem.is_empty() { "conversation".into() } else { stem.to_string() }
+}
+
+pub fn query_markdown(query: &str, summary: &str, sources: &[SearchResult]) -> String {
+    let mut out = format!("# {}\n\n{}\n", query, summary);
+    if !sources.is_empty() {
+        out.push_str(&format!("\n## Sources\n\n{}\n", sources_markdown(sources)));
+    }
+    out
+}
+
+pub fn query_json(query: &str, summary: &str, sources: &[SearchResult]) -> serde_json::Value {
+    serde_json::json!({
+        "query": query,
+        "summary": summary,
+        "results": sources,
+        "created_at": chrono::Local::now().to_rfc3339(),
+    })
+}
+
+/// Directory headless output files are written to (`OUTPUT_DIR`, default `./output` next to the binary).
+pub fn output_dir() -> PathBuf {
+    std::env::var("OUTPUT_DIR")
+        .ok()
+        .filter(|d| !d.is_empty())
+        .map(PathBuf::from)
+        .unwrap_or_else(|| crate::db::DbManager::get_storage_dir().join("output"))
+}
+
+/// Resolves a user supplied file name inside `dir`, rejecting anything
+/// that could escape it (absolute paths, `..`, drive prefixes).
+fn resolve_output_path(dir: &Path, name: &str) -> Result<PathBuf> {
+    let rel = Path::new(name);
+    if name.is_empty() || !rel.components().all(|c| matches!(c, Component::Normal(_))) {
+        return Err(anyhow!("Invalid output file '{}': must be a relative path inside the output directory", name));
+    }
+    Ok(dir.join(rel))
+}
+
+/// Writes a finished query into `output_dir()` as Markdown, or JSON when the file name ends in `.json`.
+pub fn write_query_output(name: &str, query: &str, summary: &str, sources: &[SearchResult]) -> Result<PathBuf> {
+    write_query_output_in(&output_dir(), name, query, summary, sources)
+}
+
+fn write_query_output_in(dir: &Path, name: &str, query: &str, summary: &str, sources: &[SearchResult]) -> Result<PathBuf> {
+    let path = resolve_output_path(dir, name)?;
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+        // A symlinked directory (or file) inside the output dir could still point elsewhere
+        if !parent.canonicalize()?.starts_with(dir.canonicalize()?) || path.is_symlink() {
+            return Err(anyhow!("Invalid output file '{}': resolves outside the output directory", name));
+        }
+    }
+    let body = if path.extension().is_some_and(|e| e == "json") {
+        serde_json::to_string_pretty(&query_json(query, summary, sources))?
+    } else {
+        query_markdown(query, summary, sources)
+    };
+    std::fs::write(&path, body)?;
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("bplus-export-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn output_names_cannot_escape_the_directory() {
+        let dir = Path::new("/srv/output");
+        for name in ["", "../x.md", "a/../../x.md", "/etc/passwd", "./x.md"] {
+            assert!(resolve_output_path(dir, name).is_err(), "{:?} should be rejected", name);
+        }
+        assert_eq!(resolve_output_path(dir, "runs/today.md").unwrap(), dir.join("runs/today.md"));
+    }
+
+    #[test]
+    fn extension_picks_json_or_markdown() {
+        let dir = temp_dir("format");
+        let sources = vec![SearchResult { title: "Rust".into(), url: "https://rust-lang.org".into(), ..Default::default() }];
+
+        let json = write_query_output_in(&dir, "out.json", "q", "answer", &sources).unwrap();
+        let parsed: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(json).unwrap()).unwrap();
+        assert_eq!(parsed["summary"], "answer");
+        assert_eq!(parsed["results"][0]["url"], "https://rust-lang.org");
+
+        let md = write_query_output_in(&dir, "nested/out.md", "q", "answer", &sources).unwrap();
+        let body = std::fs::read_to_string(md).unwrap();
+        assert!(body.starts_with("# q\n\nanswer"), "{}", body);
+        assert!(body.contains("- [Rust](https://rust-lang.org)"));
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinked_directories_cannot_escape() {
+        let dir = temp_dir("symlink");
+        let outside = temp_dir("symlink-target");
+        std::os::unix::fs::symlink(&outside, dir.join("link")).unwrap();
+        assert!(write_query_output_in(&dir, "link/x.md", "q", "a", &[]).is_err());
+        assert!(!outside.join("x.md").exists());
+        let _ = std::fs::remove_dir_all(dir);
+        let _ = std::fs::remove_dir_all(outside);
+    }
+
+    #[test]
+    fn citation_styles() {
+        let sources = vec![
+            SearchResult { title: "The Rust Book.".into(), url: "https://www.rust-lang.org/learn".into(), date: Some("2024-03-05".into()), engine: "DDG".into(), ..Default::default() },
+            SearchResult { title: "Notes".into(), url: "local://research.db/notes/x".into(), engine: "LocalDB".into(), ..Default::default() },
+        ];
+        let accessed = chrono::NaiveDate::from_ymd_opt(2025, 1, 2).unwrap();
+
+        let numbered = citations(&sources, "numbered", accessed).unwrap();
+        assert_eq!(numbered.lines().next(), Some("[1] The Rust Book. rust-lang.org. https://www.rust-lang.org/learn (accessed 2025-01-02)"));
+        assert_eq!(numbered.lines().nth(1), Some("[2] Notes. research.db. local://research.db/notes/x (accessed 2025-01-02)"));
+
+        let apa = citations(&sources, "apa", accessed).unwrap();
+        assert_eq!(apa.lines().next(), Some("The Rust Book. (2024, March 5). rust-lang.org. Retrieved January 2, 2025, from https://www.rust-lang.org/learn"));
+        assert!(apa.lines().nth(1).unwrap().contains("(n.d.)"));
+
+        let mla = citations(&sources[..1], "mla", accessed).unwrap();
+        assert_eq!(mla, "\"The Rust Book.\" rust-lang.org, 5 Mar. 2024, https://www.rust-lang.org/learn. Accessed 2 Jan. 2025.");
+
+        assert!(citations(&sources, "chicago", accessed).is_err());
+    }
+
+    #[test]
+    fn bibliography_escapes_titles_and_urls() {
+        let sources = vec![SearchResult {
+            title: r"50% off [C#] & $5_deals \ {x}".into(),
+            url: "https://example.com/a b)<c>{d}".into(),
+            ..Default::default()
+        }];
+        let accessed = chrono::NaiveDate::from_ymd_opt(2025, 1, 2).unwrap();
+
+        let md = bibliography_markdown(&sources, accessed);
+        assert_eq!(md, "# Bibliography\n\n1. [50% off \\[C#\\] & $5_deals \\\\ {x}](<https://example.com/a b)%3Cc%3E{d}>) (accessed 2025-01-02)\n");
+
+        let bib = bibliography_bibtex(&sources, accessed);
+        assert!(bib.contains(r"title = {50\% off [C\#] \& \$5\_deals \textbackslash{} x},"), "{}", bib);
+        assert!(bib.contains(r"howpublished = {\url{https://example.com/a b)<c>%7Bd%7D}},"), "{}", bib);
+        assert!(bib.contains("note = {Accessed: 2025-01-02}"));
+    }
+}
@@ -17,11 +17,53 @@ pub struct Model {
     pub name: String,
 }
 
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct Usage {
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub total_tokens: u64,
+}
+
+impl Usage {
+    /// Rough chars/4 estimate for providers that don't report usage.
+    pub fn estimate(prompt_chars: usize, completion_chars: usize) -> Self {
+        let prompt_tokens = prompt_chars.div_ceil(4) as u64;
+        let completion_tokens = completion_chars.div_ceil(4) as u64;
+        Self { prompt_tokens, completion_tokens, total_tokens: prompt_tokens + completion_tokens }
+    }
+}
+
+/// A provider/model pair, e.g. an entry in a query's fallback list.
+#[derive(Deserialize, Clone, Debug)]
+pub struct ModelChoice {
+    pub provider: String,
+    pub model: String,
+}
+
+/// An item from an LLM stream: answer text, the model's reasoning (thinking models),
+/// the usage report sent at the end, or notice that a failed provider was swapped for
+/// the next fallback.
+#[derive(Debug)]
+pub enum Chunk {
+    Text(String),
+    Reasoning(String),
+    Usage(Usage),
+    Fallback { provider: String, model: String, error: String },
+}
+
+type ModelProcessor = Box<dyn Fn(serde_json::Value) -> Vec<Model> + Send>;
+
 pub async fn list_models(Query(params): Query<HashMap<String, String>>) -> Json<Vec<Model>> {
     let provider = params.get("provider").map(|s| s.as_str()).unwrap_or("");
-    let client = Client::new();
+    let client = match crate::net::client_builder().build() {
+        Ok(client) => client,
+        Err(e) => {
+            tracing::error!("Failed to build HTTP client: {}", e);
+            return Json(vec![]);
+        }
+    };
 
-    let (url, headers, processor): (String, HashMap<String, String>, Box<dyn Fn(serde_json::Value) -> Vec<Model> + Send>) = match provider {
+    let (url, headers, processor): (String, HashMap<String, String>, ModelProcessor) = match provider {
         "lmstudio" => {
             let base = std::env::var("LMSTUDIO_API_BASE").unwrap_or_default();
             (
@@ -35,6 +77,18 @@ pub async fn list_models(Query(params): Query<HashMap<String, String>>) -> Json<
                 })
             )
         },
+        "ollama" => {
+            (
+                format!("{}/api/tags", ollama_base()),
+                HashMap::new(),
+                Box::new(|data| {
+                    data["models"].as_array().unwrap_or(&vec![]).iter().map(|m| Model{
+                        id: m["name"].as_str().unwrap_or("").into(),
+                        name: m["name"].as_str().unwrap_or("").into()
+                    }).collect()
+                })
+            )
+        },
         "openai" => {
             let key = std::env::var("OPENAI_API_KEY").unwrap_or_default();
             let mut h = HashMap::new(); 
@@ -46,7 +100,7 @@ pub async fn list_models(Query(params): Query<HashMap<String, String>>) -> Json<
                     data["data"].as_array().unwrap_or(&vec![]).iter()
                     .filter(|m| { 
                         let id = m["id"].as_str().unwrap_or(""); 
-                        id.starts_with("gpt") || id.starts_with("o1") 
+                        id.starts_with("gpt") || is_reasoning_model(id) 
                     })
                     .map(|m| Model{ 
                         id: m["id"].as_str().unwrap_or("").into(), 
@@ -74,6 +128,101 @@ pub async fn list_models(Query(params): Query<HashMap<String, String>>) -> Json<
                 })
             )
         },
+        "groq" => {
+            let key = std::env::var("GROQ_API_KEY").unwrap_or_default();
+            if key.is_empty() { return Json(vec![]); }
+            let mut h = HashMap::new(); 
+            h.insert("Authorization".into(), format!("Bearer {}", key));
+            (
+                "https://api.groq.com/openai/v1/models".into(), 
+                h,
+                Box::new(|data| {
+                    data["data"].as_array().unwrap_or(&vec![]).iter().map(|m| Model{ 
+                        id: m["id"].as_str().unwrap_or("").into(), 
+                        name: m["id"].as_str().unwrap_or("").into() 
+                    }).collect()
+                })
+            )
+        },
+        "mistral" => {
+            let key = std::env::var("MISTRAL_API_KEY").unwrap_or_default();
+            if key.is_empty() { return Json(vec![]); }
+            let mut h = HashMap::new(); 
+            h.insert("Authorization".into(), format!("Bearer {}", key));
+            (
+                "https://api.mistral.ai/v1/models".into(), 
+                h,
+                Box::new(|data| {
+                    data["data"].as_array().unwrap_or(&vec![]).iter().map(|m| Model{ 
+                        id: m["id"].as_str().unwrap_or("").into(), 
+                        name: m["id"].as_str().unwrap_or("").into() 
+                    }).collect()
+                })
+            )
+        },
+        "deepseek" => {
+            let key = std::env::var("DEEPSEEK_API_KEY").unwrap_or_default();
+            if key.is_empty() { return Json(vec![]); }
+            let mut h = HashMap::new(); 
+            h.insert("Authorization".into(), format!("Bearer {}", key));
+            (
+                "https://api.deepseek.com/models".into(), 
+                h,
+                Box::new(|data| {
+                    data["data"].as_array().unwrap_or(&vec![]).iter().map(|m| Model{ 
+                        id: m["id"].as_str().unwrap_or("").into(), 
+                        name: m["id"].as_str().unwrap_or("").into() 
+                    }).collect()
+                })
+            )
+        },
+        "cohere" => {
+            let key = std::env::var("COHERE_API_KEY").unwrap_or_default();
+            if key.is_empty() { return Json(vec![]); }
+            let mut h = HashMap::new(); 
+            h.insert("Authorization".into(), format!("Bearer {}", key));
+            (
+                "https://api.cohere.com/v1/models?endpoint=chat".into(), 
+                h,
+                Box::new(|data| {
+                    data["models"].as_array().unwrap_or(&vec![]).iter()
+                    .filter(|m| {
+                        m["endpoints"].as_array()
+                            .map(|a| a.iter().any(|x| x == "chat"))
+                            .unwrap_or(false)
+                    })
+                    .map(|m| Model{ 
+                        id: m["name"].as_str().unwrap_or("").into(), 
+                        name: m["name"].as_str().unwrap_or("").into() 
+                    }).collect()
+                })
+            )
+        },
+        "anthropic" => {
+            let key = std::env::var("ANTHROPIC_API_KEY").unwrap_or_default();
+            if key.is_empty() { return Json(vec![]); }
+            let mut h = HashMap::new();
+            h.insert("x-api-key".into(), key);
+            h.insert("anthropic-version".into(), ANTHROPIC_VERSION.into());
+            (
+                "https://api.anthropic.com/v1/models?limit=100".into(),
+                h,
+                Box::new(|data| {
+                    data["data"].as_array().unwrap_or(&vec![]).iter()
+                    .map(|m| Model{
+                        id: m["id"].as_str().unwrap_or("").into(),
+                        name: m["display_name"].as_str().or(m["id"].as_str()).unwrap_or("").into()
+                    }).collect()
+                })
+            )
+        },
+        "azure" => {
+            // Azure has no model listing for a key, the usable "models" are the deployments
+            let deployments = std::env::var("AZURE_OPENAI_DEPLOYMENTS").unwrap_or_default();
+            return Json(deployments.split(',').map(str::trim).filter(|d| !d.is_empty())
+                .map(|d| Model { id: d.into(), name: d.into() })
+                .collect());
+        },
         "google" => {
              let key = std::env::var("GOOGLE_API_KEY").unwrap_or_default();
              (
@@ -115,14 +264,121 @@ pub async fn list_models(Query(params): Query<HashMap<String, String>>) -> Json<
     }
 }
 
+/// Whether `provider` has what `stream_completion` needs (key, endpoint) configured.
+pub fn provider_configured(provider: &str) -> bool {
+    let set = |var: &str| std::env::var(var).is_ok_and(|v| !v.trim().is_empty());
+    match provider {
+        "lmstudio" | "ollama" => true,
+        "openai" => set("OPENAI_API_KEY"),
+        "openrouter" => set("OPENROUTER_API_KEY"),
+        "google" => set("GOOGLE_API_KEY"),
+        "groq" => set("GROQ_API_KEY"),
+        "mistral" => set("MISTRAL_API_KEY"),
+        "deepseek" => set("DEEPSEEK_API_KEY"),
+        "cohere" => set("COHERE_API_KEY"),
+        "anthropic" => set("ANTHROPIC_API_KEY"),
+        "azure" => set("AZURE_OPENAI_ENDPOINT") && set("AZURE_OPENAI_KEY"),
+        _ => false,
+    }
+}
+
+pub const PROVIDERS: &[&str] = &["lmstudio", "ollama", "openai", "openrouter", "google", "groq", "mistral", "deepseek", "azure", "cohere", "anthropic"];
+
+/// Streams a completion from `provider`/`model`. If it fails before producing anything, each
+/// of `fallbacks` is tried in turn (announced with `Chunk::Fallback`); once output has
+/// started the stream never switches provider.
+#[tracing::instrument(skip(system_prompt, history, user_prompt))]
 pub async fn stream_completion(
+    provider: &str,
+    model: &str,
+    fallbacks: &[ModelChoice],
+    system_prompt: &str,
+    history: Vec<Message>,
+    user_prompt: &str
+) -> BoxStream<'static, Result<Chunk, anyhow::Error>> {
+    let secs = |var: &str, default: u64| std::env::var(var).ok().and_then(|v| v.parse().ok()).filter(|s| *s > 0).unwrap_or(default);
+    let overall = std::time::Duration::from_secs(secs("LLM_TIMEOUT_SECS", 120));
+    let idle = std::time::Duration::from_secs(secs("LLM_IDLE_TIMEOUT_SECS", 30));
+    if fallbacks.is_empty() {
+        return with_timeouts(open_stream(provider, model, system_prompt, history, user_prompt).await, overall, idle);
+    }
+
+    let mut choices = vec![ModelChoice { provider: provider.to_string(), model: model.to_string() }];
+    choices.extend(fallbacks.iter().cloned());
+    let (system_prompt, user_prompt) = (system_prompt.to_string(), user_prompt.to_string());
+    Box::pin(async_stream::stream! {
+        for (i, choice) in choices.iter().enumerate() {
+            let mut inner = with_timeouts(
+                open_stream(&choice.provider, &choice.model, &system_prompt, history.clone(), &user_prompt).await,
+                overall, idle,
+            );
+            match inner.next().await {
+                Some(Err(e)) if i + 1 < choices.len() => {
+                    let next = &choices[i + 1];
+                    tracing::warn!("{} ({}) failed, falling back to {} ({}): {}", choice.provider, choice.model, next.provider, next.model, e);
+                    yield Ok(Chunk::Fallback { provider: next.provider.clone(), model: next.model.clone(), error: e.to_string() });
+                },
+                Some(first) => {
+                    yield first;
+                    while let Some(item) = inner.next().await { yield item; }
+                    break;
+                },
+                None => break,
+            }
+        }
+    })
+}
+
+/// Runs a one-off prompt to completion and returns the answer text.
+pub async fn complete(provider: &str, model: &str, system_prompt: &str, user_prompt: &str) -> anyhow::Result<String> {
+    let mut stream = stream_completion(provider, model, &[], system_prompt, vec![], user_prompt).await;
+    let mut text = String::new();
+    while let Some(chunk) = stream.next().await {
+        if let Chunk::Text(t) = chunk? { text.push_str(&t); }
+    }
+    Ok(text)
+}
+
+/// Ends `inner` with an error once it runs past `overall`, or goes `idle` without a chunk
+/// (which includes waiting for the response to start).
+fn with_timeouts(
+    mut inner: BoxStream<'static, Result<Chunk, anyhow::Error>>,
+    overall: std::time::Duration,
+    idle: std::time::Duration,
+) -> BoxStream<'static, Result<Chunk, anyhow::Error>> {
+    Box::pin(async_stream::stream! {
+        let deadline = tokio::time::Instant::now() + overall;
+        loop {
+            let wait_until = deadline.min(tokio::time::Instant::now() + idle);
+            match tokio::time::timeout_at(wait_until, inner.next()).await {
+                Ok(Some(item)) => yield item,
+                Ok(None) => break,
+                Err(_) => {
+                    let message = if wait_until == deadline {
+                        format!("LLM response took longer than {}s", overall.as_secs())
+                    } else {
+                        format!("LLM stopped responding for {}s", idle.as_secs())
+                    };
+                    tracing::warn!("{}", message);
+                    yield Err(anyhow::anyhow!(message));
+                    break;
+                }
+            }
+        }
+    })
+}
+
+async fn open_stream(
     provider: &str,
     model: &str,
     system_prompt: &str,
     history: Vec<Message>,
     user_prompt: &str
-) -> BoxStream<'static, Result<String, anyhow::Error>> {
-    let client = Client::new();
+) -> BoxStream<'static, Result<Chunk, anyhow::Error>> {
+    let client = match crate::net::client_builder().build() {
+        Ok(client) => client,
+        Err(e) => return Box::pin(futures::stream::once(async move { Err(anyhow::anyhow!("Failed to build HTTP client: {}", e)) })),
+    };
     
     if provider == "google" {
         let api_key = std::env::var("GOOGLE_API_KEY").unwrap_or_default();
@@ -134,79 +390,649 @@ pub async fn stream_completion(
         });
 
         let stream = try_stream_google(client, url, body);
-        return Box::pin(stream);
+        Box::pin(stream)
+    } else if provider == "ollama" {
+        let mut messages = vec![Message { role: "system".into(), content: system_prompt.into() }];
+        messages.extend(history);
+        messages.push(Message { role: "user".into(), content: user_prompt.into() });
+        let body = serde_json::json!({
+            "model": model,
+            "messages": messages,
+            "stream": true
+        });
+
+        let stream = try_stream_ollama(client, format!("{}/api/chat", ollama_base()), body);
+        Box::pin(stream)
+    } else if provider == "anthropic" {
+        let api_key = std::env::var("ANTHROPIC_API_KEY").unwrap_or_default();
+        if api_key.is_empty() {
+            return Box::pin(futures::stream::once(async { Err(anyhow::anyhow!("No API key configured for provider 'anthropic'")) }));
+        }
+
+        // The system prompt is its own field; messages may not be empty
+        let mut messages: Vec<Message> = history.into_iter().filter(|m| !m.content.trim().is_empty()).collect();
+        messages.push(Message { role: "user".into(), content: user_prompt.into() });
+        let max_tokens = std::env::var("ANTHROPIC_MAX_TOKENS").ok().and_then(|v| v.parse::<u64>().ok()).unwrap_or(4096);
+        let mut body = serde_json::json!({
+            "model": model,
+            "max_tokens": max_tokens,
+            "messages": messages,
+            "stream": true
+        });
+        if !system_prompt.trim().is_empty() {
+            body["system"] = system_prompt.into();
+        }
+
+        let stream = try_stream_anthropic(client, api_key, body);
+        Box::pin(stream)
+    } else if provider == "cohere" {
+        let api_key = std::env::var("COHERE_API_KEY").unwrap_or_default();
+        if api_key.is_empty() {
+            return Box::pin(futures::stream::once(async { Err(anyhow::anyhow!("No API key configured for provider 'cohere'")) }));
+        }
+
+        // v2 chat takes the same role/content messages as OpenAI
+        let mut messages = vec![Message { role: "system".into(), content: system_prompt.into() }];
+        messages.extend(history);
+        messages.push(Message { role: "user".into(), content: user_prompt.into() });
+        let body = serde_json::json!({
+            "model": model,
+            "messages": messages,
+            "stream": true
+        });
+
+        let stream = try_stream_cohere(client, api_key, body);
+        Box::pin(stream)
     } else {
-        // OpenAI Compatible (Local, OpenRouter, OpenAI)
+        // OpenAI Compatible (Local, OpenRouter, OpenAI, DeepSeek, Azure)
         let (api_base, api_key) = match provider {
             "openai" => ("https://api.openai.com/v1".to_string(), std::env::var("OPENAI_API_KEY").unwrap_or_default()),
             "openrouter" => ("https://openrouter.ai/api/v1".to_string(), std::env::var("OPENROUTER_API_KEY").unwrap_or_default()),
+            "groq" => ("https://api.groq.com/openai/v1".to_string(), std::env::var("GROQ_API_KEY").unwrap_or_default()),
+            "mistral" => ("https://api.mistral.ai/v1".to_string(), std::env::var("MISTRAL_API_KEY").unwrap_or_default()),
+            "deepseek" => ("https://api.deepseek.com".to_string(), std::env::var("DEEPSEEK_API_KEY").unwrap_or_default()),
+            "azure" => (std::env::var("AZURE_OPENAI_ENDPOINT").unwrap_or_default(), std::env::var("AZURE_OPENAI_KEY").unwrap_or_default()),
             _ => (std::env::var("LMSTUDIO_API_BASE").unwrap_or_else(|_| "http://localhost:1234/v1".to_string()), "not-needed".to_string()),
         };
 
-        let mut messages = vec![Message { role: "system".into(), content: system_prompt.into() }];
-        messages.extend(history);
-        messages.push(Message { role: "user".into(), content: user_prompt.into() });
+        if api_base.is_empty() {
+            let provider = provider.to_string();
+            return Box::pin(futures::stream::once(async move { Err(anyhow::anyhow!("No endpoint configured for provider '{}'", provider)) }));
+        }
+        if api_key.is_empty() {
+            let provider = provider.to_string();
+            return Box::pin(futures::stream::once(async move { Err(anyhow::anyhow!("No API key configured for provider '{}'", provider)) }));
+        }
+
+        let messages = openai_messages(model, system_prompt, history, user_prompt);
 
         let body = serde_json::json!({
             "model": model,
             "messages": messages,
-            "stream": true
+            "stream": true,
+            "stream_options": { "include_usage": true }
         });
 
-        let url = format!("{}/chat/completions", api_base);
-        let stream = try_stream_openai(client, url, api_key, body);
-        return Box::pin(stream);
+        // Azure routes by deployment (passed as the model) and authenticates with an api-key header
+        let (url, auth) = if provider == "azure" {
+            let version = std::env::var("AZURE_OPENAI_API_VERSION").unwrap_or_else(|_| "2024-10-21".to_string());
+            (
+                format!("{}/openai/deployments/{}/chat/completions?api-version={}", api_base.trim_end_matches('/'), model, version),
+                ("api-key", api_key),
+            )
+        } else {
+            (format!("{}/chat/completions", api_base), ("Authorization", format!("Bearer {}", api_key)))
+        };
+        let stream = try_stream_openai(client, url, auth, body);
+        Box::pin(stream)
     }
 }
 
-fn try_stream_openai(client: Client, url: String, key: String, body: serde_json::Value) -> impl Stream<Item = Result<String, anyhow::Error>> {
+fn try_stream_openai(client: Client, url: String, auth: (&'static str, String), body: serde_json::Value) -> impl Stream<Item = Result<Chunk, anyhow::Error>> {
     async_stream::stream! {
-        let mut req = client.post(&url).header("Authorization", format!("Bearer {}", key)).json(&body);
+        let mut req = client.post(&url).header(auth.0, auth.1.clone()).json(&body);
         if url.contains("openrouter") {
             req = req.header("HTTP-Referer", "http://localhost:3001").header("X-Title", "Bplus Search");
         }
 
         let mut source = match req.send().await {
-            Ok(resp) => resp.bytes_stream(),
-            Err(e) => { yield Err(anyhow::anyhow!(e)); return; }
+            Ok(resp) if resp.status().is_success() => resp.bytes_stream(),
+            Ok(resp) => {
+                let err = status_error(resp).await;
+                if !rejects_streaming(&err) { yield Err(err); return; }
+                // Some reasoning models can't stream; ask again for the whole answer at once
+                tracing::info!("model rejected streaming, retrying without it");
+                match complete_openai(&client, &url, auth, body).await {
+                    Ok(chunks) => for chunk in chunks { yield Ok(chunk); },
+                    Err(e) => yield Err(e),
+                }
+                return;
+            }
+            Err(e) => { tracing::warn!("LLM request failed: {}", e); yield Err(anyhow::anyhow!(e)); return; }
         };
 
-        while let Some(item) = source.next().await {
-            if let Ok(bytes) = item {
-                let chunk_str = String::from_utf8_lossy(&bytes);
-                for line in chunk_str.lines() {
-                    if line.starts_with("data: ") {
-                        let data = line.trim_start_matches("data: ").trim();
-                        if data == "[DONE]" { break; }
-                        if let Ok(json) = serde_json::from_str::<serde_json::Value>(data) {
-                            if let Some(content) = json["choices"][0]["delta"]["content"].as_str() {
-                                yield Ok(content.to_string());
+        let mut buffer = LineBuffer::default();
+        loop {
+            let (lines, done) = match source.next().await {
+                Some(Ok(bytes)) => (buffer.push(&bytes), false),
+                Some(Err(_)) => continue,
+                None => (buffer.finish().into_iter().collect(), true),
+            };
+            for line in lines {
+                let Some(data) = line.strip_prefix("data:").map(str::trim) else { continue };
+                if data == "[DONE]" { return; }
+                for chunk in parse_openai_chunk(data) { yield Ok(chunk); }
+            }
+            if done { break; }
+        }
+    }
+}
+
+/// OpenAI's o-series (`o1`, `o3-mini`, `openai/o4-mini` on OpenRouter, ...).
+fn is_reasoning_model(model: &str) -> bool {
+    let id = model.rsplit('/').next().unwrap_or(model);
+    let mut chars = id.chars();
+    chars.next() == Some('o')
+        && chars.next().is_some_and(|c| c.is_ascii_digit())
+        && chars.next().is_none_or(|c| c == '-')
+}
+
+/// Chat messages for an OpenAI-compatible request. Reasoning models reject (or, for older
+/// snapshots, ignore) the `system` role, so for them the system prompt leads the first user
+/// message instead.
+fn openai_messages(model: &str, system_prompt: &str, history: Vec<Message>, user_prompt: &str) -> Vec<Message> {
+    let mut messages = Vec::with_capacity(history.len() + 2);
+    let fold_system = is_reasoning_model(model);
+    if !fold_system {
+        messages.push(Message { role: "system".into(), content: system_prompt.into() });
+    }
+    messages.extend(history);
+    messages.push(Message { role: "user".into(), content: user_prompt.into() });
+    if fold_system && !system_prompt.trim().is_empty() {
+        if let Some(first) = messages.iter_mut().find(|m| m.role == "user") {
+            first.content = format!("{}\n\n{}", system_prompt.trim_end(), first.content);
+        }
+    }
+    messages
+}
+
+/// Whether an error from `status_error` is the API refusing `stream`.
+fn rejects_streaming(err: &anyhow::Error) -> bool {
+    let msg = err.to_string().to_lowercase();
+    msg.contains("stream") && ["unsupported", "not supported", "does not support"].iter().any(|s| msg.contains(s))
+}
+
+/// Non-streaming fallback for `try_stream_openai`: the whole answer as one text chunk plus usage.
+async fn complete_openai(client: &Client, url: &str, auth: (&'static str, String), mut body: serde_json::Value) -> anyhow::Result<Vec<Chunk>> {
+    body["stream"] = false.into();
+    if let Some(obj) = body.as_object_mut() { obj.remove("stream_options"); }
+    let resp = client.post(url).header(auth.0, auth.1).json(&body).send().await?;
+    if !resp.status().is_success() {
+        return Err(status_error(resp).await);
+    }
+    let json: serde_json::Value = resp.json().await?;
+    let message = &json["choices"][0]["message"];
+    let mut out = Vec::new();
+    if let Some(reasoning) = message["reasoning"].as_str().or(message["reasoning_content"].as_str()).filter(|r| !r.is_empty()) {
+        out.push(Chunk::Reasoning(reasoning.to_string()));
+    }
+    if let Some(content) = message["content"].as_str().filter(|c| !c.is_empty()) {
+        out.push(Chunk::Text(content.to_string()));
+    }
+    if json["usage"].is_object() {
+        let u = &json["usage"];
+        out.push(Chunk::Usage(Usage {
+            prompt_tokens: u["prompt_tokens"].as_u64().unwrap_or(0),
+            completion_tokens: u["completion_tokens"].as_u64().unwrap_or(0),
+            total_tokens: u["total_tokens"].as_u64().unwrap_or(0),
+        }));
+    }
+    Ok(out)
+}
+
+fn try_stream_cohere(client: Client, api_key: String, body: serde_json::Value) -> impl Stream<Item = Result<Chunk, anyhow::Error>> {
+    async_stream::stream! {
+        let req = client.post("https://api.cohere.com/v2/chat").bearer_auth(api_key).json(&body);
+        let mut source = match req.send().await {
+            Ok(resp) if resp.status().is_success() => resp.bytes_stream(),
+            Ok(resp) => { yield Err(status_error(resp).await); return; }
+            Err(e) => { tracing::warn!("LLM request failed: {}", e); yield Err(anyhow::anyhow!(e)); return; }
+        };
+
+        let mut buffer = LineBuffer::default();
+        loop {
+            let (lines, done) = match source.next().await {
+                Some(Ok(bytes)) => (buffer.push(&bytes), false),
+                Some(Err(_)) => continue,
+                None => (buffer.finish().into_iter().collect(), true),
+            };
+            for line in lines {
+                let Some(data) = line.strip_prefix("data:").map(str::trim) else { continue };
+                for chunk in parse_cohere_event(data) { yield Ok(chunk); }
+            }
+            if done { break; }
+        }
+    }
+}
+
+/// `OLLAMA_API_BASE` without a trailing slash, default `http://localhost:11434`.
+fn ollama_base() -> String {
+    std::env::var("OLLAMA_API_BASE").ok()
+        .filter(|b| !b.trim().is_empty())
+        .unwrap_or_else(|| "http://localhost:11434".into())
+        .trim_end_matches('/')
+        .to_string()
+}
+
+/// Ollama streams newline-delimited JSON objects rather than SSE; the last one has
+/// `"done": true` and carries the token counts.
+fn try_stream_ollama(client: Client, url: String, body: serde_json::Value) -> impl Stream<Item = Result<Chunk, anyhow::Error>> {
+    async_stream::stream! {
+        let mut source = match client.post(&url).json(&body).send().await {
+            Ok(resp) if resp.status().is_success() => resp.bytes_stream(),
+            Ok(resp) => { yield Err(status_error(resp).await); return; }
+            Err(e) => { tracing::warn!("LLM request failed: {}", e); yield Err(anyhow::anyhow!(e)); return; }
+        };
+
+        let mut buffer = LineBuffer::default();
+        loop {
+            let (lines, done) = match source.next().await {
+                Some(Ok(bytes)) => (buffer.push(&bytes), false),
+                Some(Err(_)) => continue,
+                None => (buffer.finish().into_iter().collect(), true),
+            };
+            for line in lines {
+                let Ok(json) = serde_json::from_str::<serde_json::Value>(line.trim()) else { continue };
+                if let Some(error) = json["error"].as_str() {
+                    yield Err(anyhow::anyhow!("LLM provider returned an error: {}", error));
+                    return;
+                }
+                if let Some(thinking) = json["message"]["thinking"].as_str().filter(|t| !t.is_empty()) {
+                    yield Ok(Chunk::Reasoning(thinking.to_string()));
+                }
+                if let Some(text) = json["message"]["content"].as_str().filter(|t| !t.is_empty()) {
+                    yield Ok(Chunk::Text(text.to_string()));
+                }
+                if json["done"].as_bool() == Some(true) {
+                    if let (Some(prompt_tokens), Some(completion_tokens)) = (json["prompt_eval_count"].as_u64(), json["eval_count"].as_u64()) {
+                        yield Ok(Chunk::Usage(Usage { prompt_tokens, completion_tokens, total_tokens: prompt_tokens + completion_tokens }));
+                    }
+                    return;
+                }
+            }
+            if done { break; }
+        }
+    }
+}
+
+const ANTHROPIC_VERSION: &str = "2023-06-01";
+
+fn try_stream_anthropic(client: Client, api_key: String, body: serde_json::Value) -> impl Stream<Item = Result<Chunk, anyhow::Error>> {
+    async_stream::stream! {
+        let req = client.post("https://api.anthropic.com/v1/messages")
+            .header("x-api-key", api_key)
+            .header("anthropic-version", ANTHROPIC_VERSION)
+            .json(&body);
+        let mut source = match req.send().await {
+            Ok(resp) if resp.status().is_success() => resp.bytes_stream(),
+            Ok(resp) => { yield Err(status_error(resp).await); return; }
+            Err(e) => { tracing::warn!("LLM request failed: {}", e); yield Err(anyhow::anyhow!(e)); return; }
+        };
+
+        // Input tokens arrive in `message_start`, output tokens in the closing `message_delta`
+        let mut prompt_tokens = 0;
+        let mut buffer = LineBuffer::default();
+        loop {
+            let (lines, done) = match source.next().await {
+                Some(Ok(bytes)) => (buffer.push(&bytes), false),
+                Some(Err(_)) => continue,
+                None => (buffer.finish().into_iter().collect(), true),
+            };
+            for line in lines {
+                let Some(data) = line.strip_prefix("data:").map(str::trim) else { continue };
+                let Ok(event) = serde_json::from_str::<serde_json::Value>(data) else { continue };
+                match event["type"].as_str() {
+                    Some("message_start") => {
+                        prompt_tokens = event["message"]["usage"]["input_tokens"].as_u64().unwrap_or(0);
+                    },
+                    Some("content_block_delta") => {
+                        let delta = &event["delta"];
+                        if let Some(text) = delta["text"].as_str().filter(|t| !t.is_empty()) {
+                            yield Ok(Chunk::Text(text.to_string()));
+                        } else if let Some(thinking) = delta["thinking"].as_str().filter(|t| !t.is_empty()) {
+                            yield Ok(Chunk::Reasoning(thinking.to_string()));
+                        }
+                    },
+                    Some("message_delta") => {
+                        if let Some(completion_tokens) = event["usage"]["output_tokens"].as_u64() {
+                            yield Ok(Chunk::Usage(Usage { prompt_tokens, completion_tokens, total_tokens: prompt_tokens + completion_tokens }));
+                        }
+                    },
+                    Some("error") => {
+                        let message = event["error"]["message"].as_str().unwrap_or("unknown error");
+                        yield Err(anyhow::anyhow!("LLM provider returned an error: {}", message));
+                        return;
+                    },
+                    Some("message_stop") => return,
+                    _ => {},
+                }
+            }
+            if done { break; }
+        }
+    }
+}
+
+/// Cohere streams typed events; only `content-delta` carries text and `message-end` the usage.
+fn parse_cohere_event(data: &str) -> Vec<Chunk> {
+    let mut out = Vec::new();
+    if let Ok(json) = serde_json::from_str::<serde_json::Value>(data) {
+        match json["type"].as_str() {
+            Some("content-delta") => {
+                if let Some(text) = json["delta"]["message"]["content"]["text"].as_str() {
+                    out.push(Chunk::Text(text.to_string()));
+                }
+            },
+            Some("message-end") => {
+                let tokens = &json["delta"]["usage"]["tokens"];
+                if tokens.is_object() {
+                    let prompt_tokens = tokens["input_tokens"].as_u64().unwrap_or(0);
+                    let completion_tokens = tokens["output_tokens"].as_u64().unwrap_or(0);
+                    out.push(Chunk::Usage(Usage { prompt_tokens, completion_tokens, total_tokens: prompt_tokens + completion_tokens }));
+                }
+            },
+            _ => {},
+        }
+    }
+    out
+}
+
+/// Turns a non-2xx LLM response into an error carrying the provider's own message.
+async fn status_error(resp: reqwest::Response) -> anyhow::Error {
+    let status = resp.status();
+    let body = resp.text().await.unwrap_or_default();
+    // OpenAI style `{"error": {"message"}}`; Google wraps the same shape in an array when streaming
+    let message = serde_json::from_str::<serde_json::Value>(&body).ok()
+        .and_then(|json| {
+            let err = if json.is_array() { &json[0]["error"] } else { &json["error"] };
+            err["message"].as_str().or(err.as_str()).or(json["message"].as_str()).map(String::from)
+        })
+        .unwrap_or_else(|| body.trim().chars().take(300).collect());
+    tracing::warn!(%status, "LLM request rejected: {}", message);
+    anyhow::anyhow!("LLM provider returned {}: {}", status, message)
+}
+
+fn parse_openai_chunk(data: &str) -> Vec<Chunk> {
+    let mut out = Vec::new();
+    if let Ok(json) = serde_json::from_str::<serde_json::Value>(data) {
+        let delta = &json["choices"][0]["delta"];
+        // OpenRouter calls it `reasoning`, DeepSeek and most local servers `reasoning_content`.
+        // A delta carrying both is reasoning that ended mid-chunk, so it goes first.
+        if let Some(reasoning) = delta["reasoning"].as_str().or(delta["reasoning_content"].as_str()).filter(|r| !r.is_empty()) {
+            out.push(Chunk::Reasoning(reasoning.to_string()));
+        }
+        if let Some(content) = delta["content"].as_str().filter(|c| !c.is_empty()) {
+            out.push(Chunk::Text(content.to_string()));
+        }
+        if json["usage"].is_object() {
+            let u = &json["usage"];
+            out.push(Chunk::Usage(Usage {
+                prompt_tokens: u["prompt_tokens"].as_u64().unwrap_or(0),
+                completion_tokens: u["completion_tokens"].as_u64().unwrap_or(0),
+                total_tokens: u["total_tokens"].as_u64().unwrap_or(0),
+            }));
+        }
+    }
+    out
+}
+
+/// Accumulates raw bytes and hands back only complete `\n`-terminated lines, so a
+/// frame split across network chunks (or mid UTF-8 sequence) is parsed whole.
+#[derive(Default)]
+struct LineBuffer {
+    buf: Vec<u8>,
+}
+
+impl LineBuffer {
+    fn push(&mut self, bytes: &[u8]) -> Vec<String> {
+        self.buf.extend_from_slice(bytes);
+        let mut lines = Vec::new();
+        while let Some(pos) = self.buf.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = self.buf.drain(..=pos).collect();
+            lines.push(String::from_utf8_lossy(&line).trim_end_matches(['\r', '\n']).to_string());
+        }
+        lines
+    }
+
+    /// Whatever is left once the body ends without a trailing newline.
+    fn finish(&mut self) -> Option<String> {
+        let rest = String::from_utf8_lossy(&std::mem::take(&mut self.buf)).trim().to_string();
+        if rest.is_empty() { None } else { Some(rest) }
+    }
+}
+
+/// Pulls complete top-level objects out of a streamed JSON array (`[{...},\r\n{...}]`) as
+/// they close, however the bytes are split across network chunks. Scan state is kept
+/// between pushes so each byte is looked at once.
+#[derive(Default)]
+struct JsonArrayBuffer {
+    buf: Vec<u8>,
+    pos: usize,
+    depth: usize,
+    in_string: bool,
+    escaped: bool,
+    start: Option<usize>,
+}
+
+impl JsonArrayBuffer {
+    fn push(&mut self, bytes: &[u8]) -> Vec<serde_json::Value> {
+        self.buf.extend_from_slice(bytes);
+        let mut objects = Vec::new();
+        while self.pos < self.buf.len() {
+            let b = self.buf[self.pos];
+            if self.in_string {
+                if self.escaped { self.escaped = false; }
+                else if b == b'\\' { self.escaped = true; }
+                else if b == b'"' { self.in_string = false; }
+            } else {
+                match b {
+                    b'"' if self.depth > 0 => self.in_string = true,
+                    b'{' => {
+                        if self.depth == 0 { self.start = Some(self.pos); }
+                        self.depth += 1;
+                    },
+                    b'}' if self.depth > 0 => {
+                        self.depth -= 1;
+                        if let Some(start) = self.start.filter(|_| self.depth == 0) {
+                            match serde_json::from_slice(&self.buf[start..=self.pos]) {
+                                Ok(obj) => objects.push(obj),
+                                Err(e) => tracing::warn!("Skipping malformed stream object: {}", e),
                             }
+                            self.start = None;
                         }
-                    }
+                    },
+                    _ => {},
                 }
             }
+            self.pos += 1;
+        }
+        // Keep only the object still being received
+        let consumed = self.start.unwrap_or(self.pos);
+        self.buf.drain(..consumed);
+        self.pos -= consumed;
+        self.start = self.start.map(|s| s - consumed);
+        objects
+    }
+}
+
+/// One `GenerateContentResponse`: every part's text (thought parts as reasoning) and the usage.
+fn parse_google_response(json: &serde_json::Value) -> anyhow::Result<Vec<Chunk>> {
+    if let Some(message) = json["error"]["message"].as_str() {
+        return Err(anyhow::anyhow!("LLM provider returned an error: {}", message));
+    }
+    let mut out = Vec::new();
+    for part in json["candidates"][0]["content"]["parts"].as_array().into_iter().flatten() {
+        if let Some(text) = part["text"].as_str().filter(|t| !t.is_empty()) {
+            out.push(if part["thought"].as_bool() == Some(true) {
+                Chunk::Reasoning(text.to_string())
+            } else {
+                Chunk::Text(text.to_string())
+            });
         }
     }
+    let u = &json["usageMetadata"];
+    if u.is_object() {
+        out.push(Chunk::Usage(Usage {
+            prompt_tokens: u["promptTokenCount"].as_u64().unwrap_or(0),
+            completion_tokens: u["candidatesTokenCount"].as_u64().unwrap_or(0),
+            total_tokens: u["totalTokenCount"].as_u64().unwrap_or(0),
+        }));
+    }
+    Ok(out)
 }
 
-fn try_stream_google(client: Client, url: String, body: serde_json::Value) -> impl Stream<Item = Result<String, anyhow::Error>> {
+fn try_stream_google(client: Client, url: String, body: serde_json::Value) -> impl Stream<Item = Result<Chunk, anyhow::Error>> {
     async_stream::stream! {
         let mut source = match client.post(&url).json(&body).send().await {
-             Ok(resp) => resp.bytes_stream(),
-             Err(e) => { yield Err(anyhow::anyhow!(e)); return; }
+             Ok(resp) if resp.status().is_success() => resp.bytes_stream(),
+             Ok(resp) => { yield Err(status_error(resp).await); return; }
+             Err(e) => { tracing::warn!("LLM request failed: {}", e); yield Err(anyhow::anyhow!(e)); return; }
         };
 
+        // The body is one JSON array streamed an element at a time
+        let mut buffer = JsonArrayBuffer::default();
         while let Some(item) = source.next().await {
-            if let Ok(bytes) = item {
-                let s = String::from_utf8_lossy(&bytes);
-                if let Some(start) = s.find("\"text\": \"") {
-                     let rest = &s[start + 9..];
-                     if let Some(end) = rest.find("\"") {
-                         yield Ok(rest[..end].replace("\\n", "\n").to_string());
-                     }
+            let Ok(bytes) = item else { continue };
+            for obj in buffer.push(&bytes) {
+                match parse_google_response(&obj) {
+                    Ok(chunks) => for chunk in chunks { yield Ok(chunk); },
+                    Err(e) => { yield Err(e); return; }
                 }
             }
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn collect<S: Stream<Item = Result<Chunk, anyhow::Error>>>(stream: S) -> Vec<Result<Chunk, anyhow::Error>> {
+        stream.collect().await
+    }
+
+    #[tokio::test]
+    async fn rate_limited_responses_surface_as_errors() {
+        let rejected = || async {
+            (axum::http::StatusCode::TOO_MANY_REQUESTS, axum::Json(serde_json::json!({"error": {"message": "Rate limit reached"}})))
+        };
+        let addr = crate::testutil::serve(axum::Router::new()
+            .route("/v1/chat/completions", axum::routing::post(rejected))
+            .route("/google", axum::routing::post(rejected))).await;
+
+        let openai = try_stream_openai(Client::new(), format!("http://{}/v1/chat/completions", addr), ("Authorization", "Bearer k".into()), serde_json::json!({}));
+        let google = try_stream_google(Client::new(), format!("http://{}/google", addr), serde_json::json!({}));
+        for chunks in [collect(openai).await, collect(google).await] {
+            assert_eq!(chunks.len(), 1);
+            let err = chunks[0].as_ref().unwrap_err().to_string();
+            assert!(err.contains("429") && err.contains("Rate limit reached"), "{}", err);
+        }
+    }
+
+    #[tokio::test]
+    async fn stream_that_stalls_after_opening_times_out() {
+        let addr = crate::testutil::serve(axum::Router::new().route("/v1/chat/completions", axum::routing::post(|| async {
+            let first = futures::stream::once(async {
+                Ok::<_, std::convert::Infallible>("data: {\"choices\":[{\"delta\":{\"content\":\"Hi\"}}]}\n\n")
+            });
+            axum::response::Response::builder()
+                .header("content-type", "text/event-stream")
+                .body(axum::body::Body::from_stream(first.chain(futures::stream::pending())))
+                .unwrap()
+        }))).await;
+
+        let inner = try_stream_openai(Client::new(), format!("http://{}/v1/chat/completions", addr), ("Authorization", "Bearer k".into()), serde_json::json!({}));
+        let chunks = collect(with_timeouts(Box::pin(inner), std::time::Duration::from_secs(30), std::time::Duration::from_secs(1))).await;
+        assert_eq!(chunks.len(), 2, "{:?}", chunks);
+        assert!(matches!(&chunks[0], Ok(Chunk::Text(t)) if t == "Hi"));
+        let err = chunks[1].as_ref().unwrap_err().to_string();
+        assert!(err.contains("stopped responding for 1s"), "{}", err);
+    }
+
+    fn roles_and_contents(messages: &[Message]) -> Vec<(&str, &str)> {
+        messages.iter().map(|m| (m.role.as_str(), m.content.as_str())).collect()
+    }
+
+    #[test]
+    fn chat_models_get_a_system_message() {
+        let history = vec![Message { role: "user".into(), content: "earlier".into() }, Message { role: "assistant".into(), content: "reply".into() }];
+        let messages = openai_messages("gpt-4o", "Be brief.", history, "now");
+        assert_eq!(roles_and_contents(&messages), [("system", "Be brief."), ("user", "earlier"), ("assistant", "reply"), ("user", "now")]);
+    }
+
+    #[test]
+    fn reasoning_models_get_the_system_prompt_in_the_first_user_message() {
+        let history = vec![Message { role: "user".into(), content: "earlier".into() }];
+        let messages = openai_messages("openai/o3-mini", "Be brief.", history, "now");
+        assert_eq!(roles_and_contents(&messages), [("user", "Be brief.\n\nearlier"), ("user", "now")]);
+
+        let messages = openai_messages("o1", "  ", vec![], "now");
+        assert_eq!(roles_and_contents(&messages), [("user", "now")]);
+        assert!(!is_reasoning_model("gpt-4o") && !is_reasoning_model("olmo-7b"));
+    }
+
+    #[test]
+    fn data_frame_split_mid_json_is_parsed_whole() {
+        let frame = "data: {\"choices\":[{\"delta\":{\"content\":\"Grüße\"}}]}\n\n".as_bytes();
+        // Split inside the JSON and inside the two-byte `ü`
+        let cut = frame.iter().position(|b| *b == 0xc3).unwrap() + 1;
+        let mut buffer = LineBuffer::default();
+        assert!(buffer.push(&frame[..cut]).is_empty());
+        let lines = buffer.push(&frame[cut..]);
+        let data = lines[0].strip_prefix("data:").unwrap().trim();
+        assert!(matches!(parse_openai_chunk(data).as_slice(), [Chunk::Text(t)] if t == "Grüße"));
+        assert_eq!(buffer.finish(), None);
+    }
+
+    #[tokio::test]
+    async fn split_frames_are_still_streamed() {
+        let addr = crate::testutil::serve(axum::Router::new().route("/v1/chat/completions", axum::routing::post(|| async {
+            let parts = ["data: {\"choices\":[{\"delta\":{\"con", "tent\":\"Hello\"}}]}\n\ndata: [DONE]\n\n"];
+            let body = futures::stream::iter(parts).then(|p| async move {
+                tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+                Ok::<_, std::convert::Infallible>(p)
+            });
+            axum::response::Response::builder()
+                .header("content-type", "text/event-stream")
+                .body(axum::body::Body::from_stream(body))
+                .unwrap()
+        }))).await;
+        let chunks = collect(try_stream_openai(Client::new(), format!("http://{}/v1/chat/completions", addr), ("Authorization", "Bearer k".into()), serde_json::json!({}))).await;
+        assert_eq!(chunks.len(), 1, "{:?}", chunks);
+        assert!(matches!(&chunks[0], Ok(Chunk::Text(t)) if t == "Hello"));
+    }
+
+    #[test]
+    fn json_array_objects_come_out_as_they_close() {
+        let stream = b"[{\"text\": \"a } in a string\"},\r\n{\"nested\": {\"x\": \"\\\"{\"}},\r\n{\"last\": 1}]";
+        let mut buffer = JsonArrayBuffer::default();
+        let mut objects = Vec::new();
+        // One byte at a time is the worst case for chunk boundaries
+        for b in stream.iter() {
+            objects.extend(buffer.push(std::slice::from_ref(b)));
+        }
+        assert_eq!(objects, [
+            serde_json::json!({"text": "a } in a string"}),
+            serde_json::json!({"nested": {"x": "\"{"}}),
+            serde_json::json!({"last": 1}),
+        ]);
+        assert!(buffer.buf.is_empty());
+    }
+
+    #[test]
+    fn usage_estimate_rounds_up_to_whole_tokens() {
+        let usage = Usage::estimate(10, 1);
+        assert_eq!((usage.prompt_tokens, usage.completion_tokens, usage.total_tokens), (3, 1, 4));
+        let usage = Usage::estimate(0, 0);
+        assert_eq!(usage.total_tokens, 0);
+    }
+}
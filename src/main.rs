@@ -1,17 +1,25 @@
 use axum::{
-    extract::{Path, State},
+    extract::{Path, Query, State},
     http::{StatusCode, Uri},
     response::{IntoResponse, Sse},
-    routing::{get, post, put, delete},
+    routing::{get, post, put, patch, delete},
     Json, Router,
 };
 use rust_embed::RustEmbed;
-use std::{net::SocketAddr, sync::Arc};
+use std::{collections::HashMap, net::SocketAddr, sync::{Arc, Mutex}};
+use tokio_util::sync::CancellationToken;
 use tower_http::cors::CorsLayer;
 
 mod db;
+mod export;
 mod llm;
+mod metrics;
+mod net;
+mod proxy;
 mod search;
+mod transform;
+#[cfg(test)]
+mod testutil;
 
 #[derive(RustEmbed)]
 #[folder = "public/"]
@@ -19,37 +27,192 @@ struct Asset;
 
 struct AppState {
     db: db::DbManager,
+    http: reqwest::Client,
+    /// Running queries keyed by conversation id, with a sequence number so a finishing
+    /// query never unregisters a newer one for the same conversation.
+    active_queries: Mutex<HashMap<i64, (u64, CancellationToken)>>,
+    query_seq: std::sync::atomic::AtomicU64,
+    /// Shared across requests so concurrent searches don't hammer the same scraped engine
+    limiter: net::RateLimiter,
+    metrics: metrics::Metrics,
 }
 
-#[tokio::main]
-async fn main() {
-    dotenvy::dotenv().ok();
-    let db_manager = db::DbManager::new();
-    db_manager.init_schema().expect("Failed to init DB");
-    let state = Arc::new(AppState { db: db_manager });
-
-    let app = Router::new()
+/// Every route with its middleware, ready to serve.
+fn app(state: Arc<AppState>, cors: CorsLayer) -> Router {
+    let api = Router::new()
+        .route("/api/config", get(handlers::config))
         .route("/api/models", get(llm::list_models))
         .route("/api/suggest", get(search::suggest))
+        .route("/api/search/sources", get(db::routes::search_sources))
+        .route("/api/queries/recent", get(db::routes::recent_queries))
         .route("/api/conversations", get(db::routes::list_conversations).post(db::routes::create_conversation))
+        .route("/api/conversations/import", post(db::routes::import_conversation))
         .route("/api/conversations/:id", get(db::routes::get_conversation).delete(db::routes::delete_conversation))
+        .route("/api/conversations/:id/clear", post(db::routes::clear_conversation))
+        .route("/api/conversations/:id/flags", patch(db::routes::set_flags))
+        .route("/api/conversations/:id/tags", post(db::routes::add_tag))
+        .route("/api/conversations/:id/tags/:tag_id", delete(db::routes::remove_tag))
         .route("/api/conversations/:id/notes", put(db::routes::save_note))
+        .route("/api/notes/export", get(db::routes::export_notes))
+        .route("/api/conversations/:id/notes/history", get(db::routes::note_history))
+        .route("/api/conversations/:id/bibliography", get(db::routes::bibliography))
+        .route("/api/conversations/:id/export", get(db::routes::export_conversation))
+        .route("/api/conversations/:id/messages/:mid/sources", get(db::routes::message_sources))
+        .route("/api/conversations/:id/messages/:mid/citations", get(db::routes::citations))
         .route("/api/conversations/:id/query", post(handlers::handle_query))
+        .route("/api/conversations/:id/regenerate", post(handlers::regenerate))
+        .route("/api/conversations/:id/continue", post(handlers::continue_answer))
+        .route("/api/conversations/:id/stop", post(handlers::stop_query))
+        .route("/api/prompts", get(db::routes::list_prompts).post(db::routes::add_prompt))
+        .route("/api/prompts/:id", delete(db::routes::delete_prompt))
+        .route("/api/bookmarks", get(db::routes::list_bookmarks).post(db::routes::add_bookmark))
+        .route("/api/bookmarks/:id", delete(db::routes::delete_bookmark))
+        .route("/api/conversations/:id/pinned", get(db::routes::list_pinned_sources).post(db::routes::add_pinned_source))
+        .route("/api/conversations/:id/pinned/:pin_id", delete(db::routes::delete_pinned_source))
         .route("/api/providers", get(db::routes::list_providers).post(db::routes::add_provider))
-        .route("/api/providers/:id", delete(db::routes::delete_provider))
+        .route("/api/providers/:id", put(db::routes::update_provider).patch(db::routes::patch_provider).delete(db::routes::delete_provider))
+        .route("/api/providers/:id/enabled", patch(db::routes::set_provider_enabled))
         .route("/api/research/save", post(db::routes::save_db))
         .route("/api/research/load", post(db::routes::load_db))
         .route("/api/research/files", get(db::routes::list_db_files))
+        .route("/api/research/current", get(db::routes::current_db))
+        .route("/api/research/files/:filename", delete(db::routes::delete_db_file))
+        .route("/v1/chat/completions", post(proxy::chat_completions));
+
+    let api = if state.metrics.enabled() { api.route("/metrics", get(metrics::metrics)) } else { api };
+
+    // Static assets stay public, everything else needs the key when one is configured
+    let api = match std::env::var("SERVER_API_KEY").ok().filter(|k| !k.is_empty()) {
+        Some(key) => {
+            let key: Arc<str> = key.into();
+            api.route_layer(axum::middleware::from_fn(move |req, next| require_api_key(key.clone(), req, next)))
+        },
+        None => api,
+    };
+
+    Router::new()
+        .merge(api)
         .route("/", get(index_handler))
         .route("/index.html", get(index_handler))
         .fallback(static_handler)
-        .layer(CorsLayer::permissive())
-        .with_state(state);
+        // The default predicate skips text/event-stream, so SSE chunks still flush as they're produced
+        .layer(tower_http::compression::CompressionLayer::new())
+        .layer(cors)
+        .layer(tower_http::trace::TraceLayer::new_for_http())
+        .with_state(state)
+}
 
-    let port = 3001;
-    println!("Server running at http://localhost:{}", port);
-    let listener = tokio::net::TcpListener::bind(SocketAddr::from(([0, 0, 0, 0], port))).await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+#[tokio::main]
+async fn main() {
+    dotenvy::dotenv().ok();
+    tracing_subscriber::fmt()
+        .with_env_filter(tracing_subscriber::EnvFilter::try_from_default_env().unwrap_or_else(|_| "info".into()))
+        .init();
+    let db_manager = db::DbManager::new();
+    db_manager.init_schema().expect("Failed to init DB");
+    let http = net::client_builder()
+        .user_agent("bplus-native/1.0")
+        .timeout(std::time::Duration::from_secs(15))
+        .build()
+        .expect("Failed to build HTTP client");
+    let state = Arc::new(AppState {
+        db: db_manager,
+        http,
+        active_queries: Mutex::new(HashMap::new()),
+        query_seq: Default::default(),
+        limiter: net::RateLimiter::from_env(),
+        metrics: metrics::Metrics::from_env(),
+    });
+
+    let cors = cors_layer().unwrap_or_else(|e| {
+        tracing::error!("{}", e);
+        std::process::exit(1);
+    });
+    let app = app(state.clone(), cors);
+
+    let port: u16 = match std::env::var("PORT") {
+        Ok(p) if !p.trim().is_empty() => p.trim().parse().unwrap_or_else(|_| {
+            tracing::error!("Invalid PORT '{}': expected a number between 0 and 65535", p);
+            std::process::exit(1);
+        }),
+        _ => 3001,
+    };
+    let bind_addr: std::net::IpAddr = match std::env::var("BIND_ADDR") {
+        Ok(a) if !a.trim().is_empty() => a.trim().parse().unwrap_or_else(|_| {
+            tracing::error!("Invalid BIND_ADDR '{}': expected an IP address like 127.0.0.1", a);
+            std::process::exit(1);
+        }),
+        _ => [0, 0, 0, 0].into(),
+    };
+    let listener = tokio::net::TcpListener::bind(SocketAddr::new(bind_addr, port)).await.unwrap_or_else(|e| {
+        tracing::error!("Failed to bind {}:{}: {}", bind_addr, port, e);
+        std::process::exit(1);
+    });
+    tracing::info!("Server running at http://{}", listener.local_addr().unwrap());
+    axum::serve(listener, app)
+        .with_graceful_shutdown(shutdown_signal())
+        .await
+        .unwrap();
+
+    // In-flight requests (including backups) have finished; fold the WAL back into the file
+    if let Err(e) = state.db.checkpoint() {
+        tracing::warn!("WAL checkpoint on shutdown failed: {}", e);
+    }
+}
+
+/// Permissive unless `ALLOWED_ORIGINS` lists the origins allowed to call the API (with credentials).
+fn cors_layer() -> anyhow::Result<CorsLayer> {
+    let Some(raw) = std::env::var("ALLOWED_ORIGINS").ok().filter(|o| !o.trim().is_empty()) else {
+        return Ok(CorsLayer::permissive());
+    };
+    let origins = raw.split(',')
+        .map(|o| o.trim().trim_end_matches('/'))
+        .filter(|o| !o.is_empty())
+        .map(|o| o.parse::<axum::http::HeaderValue>().map_err(|_| anyhow::anyhow!("Invalid origin '{}' in ALLOWED_ORIGINS", o)))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    // Credentials rule out wildcards, so mirror what the preflight asks for instead
+    Ok(CorsLayer::new()
+        .allow_origin(origins)
+        .allow_methods(tower_http::cors::AllowMethods::mirror_request())
+        .allow_headers(tower_http::cors::AllowHeaders::mirror_request())
+        .allow_credentials(true))
+}
+
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c().await.expect("Failed to install Ctrl+C handler");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Failed to install SIGTERM handler")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+    tracing::info!("shutting down gracefully");
+}
+
+/// Rejects requests without `Authorization: Bearer <SERVER_API_KEY>`.
+async fn require_api_key(key: Arc<str>, req: axum::extract::Request, next: axum::middleware::Next) -> axum::response::Response {
+    let given = req.headers().get(axum::http::header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .unwrap_or("");
+    // Compare without short-circuiting so response timing doesn't leak the key
+    let matches = given.len() == key.len()
+        && given.bytes().zip(key.bytes()).fold(0u8, |acc, (a, b)| acc | (a ^ b)) == 0;
+    if !matches {
+        return (StatusCode::UNAUTHORIZED, Json(serde_json::json!({ "error": "Missing or invalid API key" }))).into_response();
+    }
+    next.run(req).await
 }
 
 async fn index_handler() -> impl IntoResponse { static_handler(Uri::from_static("/index.html")).await }
@@ -66,95 +229,601 @@ async fn static_handler(uri: Uri) -> impl IntoResponse {
 mod handlers {
     use super::*;
     use axum::response::sse::{Event, KeepAlive};
-    use futures::stream::Stream;
+    use futures::stream::{Stream, StreamExt};
     use serde::Deserialize;
 
     #[derive(Deserialize)]
     pub struct QueryRequest {
         query: String,
+        #[serde(flatten)]
+        options: QueryOptions,
+    }
+
+    /// Everything about a query except its text, shared by `query`, `regenerate` and `continue`
+    /// (which only uses the LLM fields).
+    #[derive(Deserialize)]
+    pub struct QueryOptions {
         timeframe: Option<String>,
+        /// Result language (`de`, `pt-BR`) and country (`de`, `us`) for engines that support them
+        lang: Option<String>,
+        region: Option<String>,
+        /// Keep only results from these domains / drop results from these (subdomains match too)
+        #[serde(default)]
+        include_domains: Vec<String>,
+        #[serde(default)]
+        exclude_domains: Vec<String>,
         providers: Option<Vec<i64>>,
         provider: String, 
         model: String,    
-        #[serde(rename = "systemPrompt")]
+        /// Tried in order when the provider above fails before answering
+        #[serde(default)]
+        fallback_providers: Vec<crate::llm::ModelChoice>,
+        #[serde(rename = "systemPrompt", default)]
         system_prompt: String,
+        /// Saved prompt used when `systemPrompt` is empty
+        preset_id: Option<i64>,
+        /// Sources kept for the UI and the prompt, clamped to `1..=MAX_RESULTS_CAP` (default `MAX_SEARCH_RESULTS` or 15)
+        max_results: Option<usize>,
+        /// Fetch the top results' pages and use their article text instead of the snippet
+        fetch_content: Option<bool>,
+        /// Reorder results by embedding similarity to the query (needs `EMBEDDINGS_*`)
+        rerank: Option<bool>,
+        /// Also search up to two LLM-written rephrasings of the query and merge the results
+        expand: Option<bool>,
+        /// After the summary, ask the model how well the sources support it (`confidence` event)
+        assess_confidence: Option<bool>,
+        /// Local Database only: keep message hits from this role (`assistant` or `user`)
+        local_db_role: Option<String>,
+        /// Local Database only: rank assistant answers above user messages
+        local_db_prefer_assistant: Option<bool>,
+    }
+
+    /// Upper bound for a request's `max_results`, anything more just bloats the prompt.
+    pub const MAX_RESULTS_CAP: usize = 50;
+
+    pub fn default_max_results() -> usize {
+        std::env::var("MAX_SEARCH_RESULTS").ok().and_then(|v| v.parse().ok()).unwrap_or(15)
+    }
+
+    pub fn default_snippet_max() -> usize {
+        std::env::var("SNIPPET_MAX_CHARS").ok().and_then(|v| v.parse().ok()).unwrap_or(500)
+    }
+
+    /// Puts the conversation's pinned sources at the top of `results` and cuts the list to
+    /// `max_results`, never cutting a pin. Fetched copies of a pin are dropped, and a pin saved
+    /// without a snippet borrows the one the search found.
+    pub fn with_pinned(mut results: Vec<crate::search::SearchResult>, mut pinned: Vec<crate::search::SearchResult>, max_results: usize) -> Vec<crate::search::SearchResult> {
+        for p in pinned.iter_mut().filter(|p| p.content.is_empty()) {
+            if let Some(found) = results.iter().find(|r| r.url == p.url) {
+                p.content = found.content.clone();
+            }
+        }
+        let pinned_urls: std::collections::HashSet<String> = pinned.iter().map(|p| p.url.clone()).collect();
+        results.retain(|r| !pinned_urls.contains(&r.url));
+        let keep = max_results.max(pinned.len());
+        pinned.extend(results);
+        pinned.truncate(keep);
+        pinned
+    }
+
+    pub fn build_user_prompt(query: &str, search_results: &[crate::search::SearchResult], snippet_max: usize) -> String {
+        let current_date = chrono::Local::now().format("%Y-%m-%d").to_string();
+
+        if search_results.is_empty() {
+            // Pure Chat Mode (No Search Results)
+            format!(
+                "Current Date: {}\nQuery: \"{}\"\n\nNo external search results were used for this response. Please answer the query using your internal knowledge.", 
+                current_date, query
+            )
+        } else {
+            // RAG Mode (With Search Results)
+            let snippets: String = search_results.iter()
+                .map(|r| format!("[{}] {}\nURL: {}\nSnippet: {}", r.engine, r.title, r.url, crate::search::truncate_snippet(&r.content, snippet_max)))
+                .collect::<Vec<_>>().join("\n\n---\n\n");
+            
+            format!(
+                "Current Date: {}\nQuery: \"{}\"\n\nBased on the following search results, write a clear, concise summary answering the query. If results mention this date, they are current.\n\nSearch Results:\n{}", 
+                current_date, query, snippets
+            )
+        }
+    }
+
+    /// Follow-up call rating how well the sources support `summary`: `{level, caveats[]}` with
+    /// `level` one of high/medium/low.
+    async fn assess_confidence(req: &QueryOptions, query: &str, summary: &str, sources: &[crate::search::SearchResult]) -> anyhow::Result<serde_json::Value> {
+        let system = "You review research summaries against their sources. Reply with JSON only.";
+        let prompt = format!(
+            "{}\n\nSummary to review:\n{}\n\nHow well do the search results above support this summary? Consider whether \
+            sources are thin, outdated or contradict each other. Reply with exactly one JSON object: \
+            {{\"level\": \"high\" | \"medium\" | \"low\", \"caveats\": [\"short caveat\", ...]}}",
+            build_user_prompt(query, sources, default_snippet_max()), summary
+        );
+        let reply = crate::llm::complete(&req.provider, &req.model, system, &prompt).await?;
+        // Models like to wrap JSON in prose or code fences; take the outermost object
+        let json = reply.find('{').zip(reply.rfind('}')).filter(|(start, end)| start < end)
+            .and_then(|(start, end)| serde_json::from_str::<serde_json::Value>(&reply[start..=end]).ok())
+            .ok_or_else(|| anyhow::anyhow!("confidence reply is not JSON"))?;
+        let level = json["level"].as_str().map(|l| l.trim().to_lowercase())
+            .filter(|l| ["high", "medium", "low"].contains(&l.as_str()))
+            .ok_or_else(|| anyhow::anyhow!("confidence reply has no valid level"))?;
+        let caveats: Vec<String> = json["caveats"].as_array().into_iter().flatten()
+            .filter_map(|c| c.as_str()).map(|c| c.trim().to_string()).filter(|c| !c.is_empty())
+            .collect();
+        Ok(serde_json::json!({ "level": level, "caveats": caveats }))
+    }
+
+    /// `GET /api/config`: which optional integrations are configured and the effective limits.
+    /// Only booleans, never the keys themselves.
+    pub async fn config() -> Json<serde_json::Value> {
+        let set = |var: &str| std::env::var(var).is_ok_and(|v| !v.trim().is_empty());
+        let llm: serde_json::Map<String, serde_json::Value> = crate::llm::PROVIDERS.iter()
+            .map(|p| (p.to_string(), crate::llm::provider_configured(p).into()))
+            .collect();
+        Json(serde_json::json!({
+            "llm_providers": llm,
+            "search": {
+                "searxng": !crate::search::searxng_instances().is_empty(),
+                "youtube": set("YOUTUBE_API_KEY"),
+                "marginalia": set("MARGINALIA_API_KEY"),
+                "rerank": crate::search::rerank_configured(),
+                "github_token": set("GITHUB_TOKEN"),
+                "semantic_scholar_key": set("SEMANTIC_SCHOLAR_API_KEY"),
+            },
+            "limits": {
+                "max_results": default_max_results(),
+                "snippet_max_chars": default_snippet_max(),
+                "deep_read_max_chars": crate::search::deep_read_max_chars(),
+            },
+        }))
+    }
+
+    /// Registers a query as running and unregisters it when dropped, which also
+    /// covers the client disconnecting mid-stream.
+    struct ActiveQuery { state: Arc<AppState>, conversation_id: i64, seq: u64 }
+
+    impl ActiveQuery {
+        fn start(state: &Arc<AppState>, conversation_id: i64) -> (Self, CancellationToken) {
+            let token = CancellationToken::new();
+            let seq = state.query_seq.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            let previous = state.active_queries.lock().unwrap_or_else(|e| e.into_inner())
+                .insert(conversation_id, (seq, token.clone()));
+            // Only one query streams per conversation, a new one supersedes the old
+            if let Some((_, old)) = previous { old.cancel(); }
+            (Self { state: state.clone(), conversation_id, seq }, token)
+        }
+    }
+
+    impl Drop for ActiveQuery {
+        fn drop(&mut self) {
+            let mut active = self.state.active_queries.lock().unwrap_or_else(|e| e.into_inner());
+            if active.get(&self.conversation_id).is_some_and(|(seq, _)| *seq == self.seq) {
+                active.remove(&self.conversation_id);
+            }
+        }
+    }
+
+    /// Stops the running query for a conversation, if any. The partial summary is still saved.
+    pub async fn stop_query(Path(conversation_id): Path<i64>, State(state): State<Arc<AppState>>) -> Json<serde_json::Value> {
+        let token = state.active_queries.lock().unwrap_or_else(|e| e.into_inner())
+            .get(&conversation_id).map(|(_, t)| t.clone());
+        let stopped = token.is_some();
+        if let Some(t) = token { t.cancel(); }
+        Json(serde_json::json!({ "stopped": stopped }))
+    }
+
+    #[derive(Deserialize)]
+    pub struct ResponseParams { stream: Option<bool> }
+
+    /// Clients that can't read SSE get one JSON object instead, via `?stream=false` or
+    /// `Accept: application/json`.
+    fn wants_json(headers: &axum::http::HeaderMap, params: &ResponseParams) -> bool {
+        params.stream == Some(false) || headers.get(axum::http::header::ACCEPT)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|a| a.contains("application/json") && !a.contains("text/event-stream"))
+    }
+
+    /// Fills in the system prompt from `preset_id` unless one was sent explicitly.
+    fn resolve_preset(state: &super::AppState, mut options: QueryOptions) -> Result<QueryOptions, crate::db::routes::ApiError> {
+        if let Some(id) = options.preset_id.filter(|_| options.system_prompt.trim().is_empty()) {
+            options.system_prompt = state.db.prompt_preset(id)?
+                .ok_or_else(|| crate::db::routes::ApiError::not_found("Prompt preset not found"))?;
+        }
+        Ok(options)
     }
 
     pub async fn handle_query(
         Path(conversation_id): Path<i64>,
+        Query(params): Query<ResponseParams>,
         State(state): State<Arc<super::AppState>>,
+        headers: axum::http::HeaderMap,
         Json(req): Json<QueryRequest>,
-    ) -> Sse<impl Stream<Item = Result<Event, axum::BoxError>>> {
-        
+    ) -> Result<axum::response::Response, crate::db::routes::ApiError> {
+        let options = resolve_preset(&state, req.options)?;
+        state.metrics.record_query();
         let _ = state.db.add_message(conversation_id, "user", &req.query, None);
+        let events = answer_events(state, conversation_id, &headers, req.query, options, true);
+        Ok(respond(events, wants_json(&headers, &params)).await)
+    }
+
+    /// Replaces the last answer with a fresh one for the same user message.
+    pub async fn regenerate(
+        Path(conversation_id): Path<i64>,
+        Query(params): Query<ResponseParams>,
+        State(state): State<Arc<super::AppState>>,
+        headers: axum::http::HeaderMap,
+        Json(options): Json<QueryOptions>,
+    ) -> Result<axum::response::Response, crate::db::routes::ApiError> {
+        let options = resolve_preset(&state, options)?;
+        let query = state.db.rewind_to_last_user_message(conversation_id)?
+            .ok_or_else(|| crate::db::routes::ApiError::conflict("Conversation has no user message to regenerate"))?;
+        let events = answer_events(state, conversation_id, &headers, query, options, false);
+        Ok(respond(events, wants_json(&headers, &params)).await)
+    }
+
+    /// Picks up a cut-off answer: the model sees the conversation so far and is asked to carry
+    /// on, and what it writes is appended to the last assistant message.
+    pub async fn continue_answer(
+        Path(conversation_id): Path<i64>,
+        Query(params): Query<ResponseParams>,
+        State(state): State<Arc<super::AppState>>,
+        headers: axum::http::HeaderMap,
+        Json(options): Json<QueryOptions>,
+    ) -> Result<axum::response::Response, crate::db::routes::ApiError> {
+        let options = resolve_preset(&state, options)?;
+        let (message_id, _, sources) = state.db.last_assistant_message(conversation_id)?
+            .ok_or_else(|| crate::db::routes::ApiError::conflict("Conversation has no answer to continue"))?;
+        let sources: Vec<crate::search::SearchResult> = sources.as_deref()
+            .and_then(|s| serde_json::from_str(s).ok()).unwrap_or_default();
+        let events = continue_events(state, conversation_id, message_id, sources, options);
+        Ok(respond(events, wants_json(&headers, &params)).await)
+    }
+
+    fn build_continue_prompt(sources: &[crate::search::SearchResult]) -> String {
+        let mut prompt = String::from(
+            "Your previous answer was cut off. Continue it exactly where it stopped, without repeating \
+            anything already written and without any preamble. Keep the same formatting and citation numbers."
+        );
+        if !sources.is_empty() {
+            prompt.push_str("\n\nSources:\n");
+            for (i, s) in sources.iter().enumerate() {
+                prompt.push_str(&format!("[{}] {} - {}\n", i + 1, s.title, s.url));
+            }
+        }
+        prompt
+    }
+
+    /// LLM half of `answer_events` for `continue`: no search, the text is appended to `message_id`.
+    fn continue_events(
+        state: Arc<super::AppState>,
+        conversation_id: i64,
+        message_id: i64,
+        sources: Vec<crate::search::SearchResult>,
+        req: QueryOptions,
+    ) -> impl Stream<Item = (&'static str, serde_json::Value)> {
+        let (active, cancel) = ActiveQuery::start(&state, conversation_id);
+
+        async_stream::stream! {
+            let _active = active;
+            yield ("results", serde_json::json!(sources));
+
+            // History ends with the partial answer, so the model continues its own text
+            let history = state.db.get_history(conversation_id).unwrap_or_default();
+            let user_prompt = build_continue_prompt(&sources);
+
+            let mut full_text = String::new();
+            let mut llm = std::pin::pin!(llm_events(&state, &req, history, user_prompt, cancel.clone()));
+            while let Some((name, data)) = llm.next().await {
+                if name == "summary-chunk" { full_text.push_str(data["text"].as_str().unwrap_or("")); }
+                yield (name, data);
+            }
+
+            if !full_text.is_empty() {
+                if let Err(e) = state.db.append_to_message(message_id, &full_text) {
+                    yield ("error", serde_json::json!({"message": e.to_string()}));
+                }
+            }
+            yield ("summary-done", serde_json::json!({"messageId": message_id, "stopped": cancel.is_cancelled()}));
+        }
+    }
+
+    /// The LLM step shared by every answer path: `summary-start`, then the streamed
+    /// `summary-chunk`/`reasoning-chunk`/`provider-fallback`/`error` events until the model
+    /// finishes or `cancel` fires, then `usage` (estimated when the provider sends none).
+    fn llm_events(
+        state: &Arc<super::AppState>,
+        req: &QueryOptions,
+        history: Vec<crate::llm::Message>,
+        user_prompt: String,
+        cancel: CancellationToken,
+    ) -> impl Stream<Item = (&'static str, serde_json::Value)> {
+        let state = state.clone();
+        let (provider, model, fallbacks, system_prompt) = (req.provider.clone(), req.model.clone(), req.fallback_providers.clone(), req.system_prompt.clone());
+
+        async_stream::stream! {
+            yield ("summary-start", serde_json::json!({}));
+
+            let mut text_len = 0;
+            let mut usage = None;
+            let prompt_chars = system_prompt.len() + user_prompt.len() + history.iter().map(|m| m.content.len()).sum::<usize>();
+            let llm_started = std::time::Instant::now();
+            let mut llm_stream = crate::llm::stream_completion(&provider, &model, &fallbacks, &system_prompt, history, &user_prompt).await;
+
+            loop {
+                let chunk = tokio::select! {
+                    _ = cancel.cancelled() => None,
+                    c = llm_stream.next() => c,
+                };
+                let Some(chunk) = chunk else { break };
+                match chunk {
+                    Ok(crate::llm::Chunk::Text(text)) => {
+                        text_len += text.len();
+                        yield ("summary-chunk", serde_json::json!({"text": text}));
+                    },
+                    Ok(crate::llm::Chunk::Reasoning(text)) => {
+                        yield ("reasoning-chunk", serde_json::json!({"text": text}));
+                    },
+                    Ok(crate::llm::Chunk::Usage(u)) => usage = Some(u),
+                    Ok(crate::llm::Chunk::Fallback { provider, model, error }) => {
+                        yield ("provider-fallback", serde_json::json!({"provider": provider, "model": model, "error": error}));
+                    },
+                    Err(e) => {
+                        yield ("error", serde_json::json!({"message": e.to_string()}));
+                    }
+                }
+            }
+
+            let usage = usage.unwrap_or_else(|| crate::llm::Usage::estimate(prompt_chars, text_len));
+            state.metrics.record_llm(llm_started.elapsed(), &usage);
+            yield ("usage", serde_json::json!(usage));
+        }
+    }
+
+    /// Streams the events as SSE, or runs them to completion and folds them into
+    /// `{summary, results, messageId, usage, stopped, error?, fallback?, confidence?}`.
+    async fn respond(events: impl Stream<Item = (&'static str, serde_json::Value)> + Send + 'static, json: bool) -> axum::response::Response {
+        if !json {
+            let stream = events.map(|(name, data)| Ok::<_, axum::BoxError>(Event::default().event(name).json_data(data).unwrap()));
+            return Sse::new(stream).keep_alive(KeepAlive::default()).into_response();
+        }
+        let mut out = serde_json::json!({ "summary": "", "results": [], "messageId": null });
+        let mut summary = String::new();
+        let mut events = std::pin::pin!(events);
+        while let Some((name, data)) = events.next().await {
+            match name {
+                "results" => out["results"] = data,
+                "summary-chunk" => summary.push_str(data["text"].as_str().unwrap_or("")),
+                "usage" => out["usage"] = data,
+                "confidence" => out["confidence"] = data,
+                "provider-fallback" => out["fallback"] = data,
+                "output-written" => out["outputPath"] = data["path"].clone(),
+                "error" => out["error"] = data["message"].clone(),
+                "summary-done" => {
+                    out["messageId"] = data["messageId"].clone();
+                    out["stopped"] = data["stopped"].clone();
+                },
+                _ => {},
+            }
+        }
+        out["summary"] = summary.into();
+        Json(out).into_response()
+    }
+
+    /// Search + summarize pipeline for a user message already stored in the conversation,
+    /// as `(event name, payload)` pairs.
+    fn answer_events(
+        state: Arc<super::AppState>,
+        conversation_id: i64,
+        headers: &axum::http::HeaderMap,
+        query: String,
+        req: QueryOptions,
+        log_query: bool,
+    ) -> impl Stream<Item = (&'static str, serde_json::Value)> {
+        // Headless runs can ask for the finished query to also be written to disk
+        let output_file = headers.get("x-output-file").and_then(|v| v.to_str().ok()).map(String::from);
+        let (active, cancel) = ActiveQuery::start(&state, conversation_id);
 
         let stream = async_stream::stream! {
+            let _active = active;
             // Get providers (or empty list if user unchecked everything)
-            let providers_config = state.db.get_providers(req.providers).unwrap_or_default();
-            
-            let client = reqwest::Client::builder()
-                .user_agent("bplus-native/1.0")
-                .timeout(std::time::Duration::from_secs(15))
-                .build()
-                .unwrap();
+            let providers_config = state.db.get_providers(req.providers.clone()).unwrap_or_default();
             
+            let client = state.http.clone();
+            let search_opts = crate::search::SearchOptions {
+                timeframe: req.timeframe.clone(),
+                lang: req.lang.clone(),
+                region: req.region.clone(),
+                include_domains: req.include_domains.clone(),
+                exclude_domains: req.exclude_domains.clone(),
+                local_db_files: state.db.local_search_scope(),
+                local_db_role: req.local_db_role.clone(),
+                local_db_prefer_assistant: req.local_db_prefer_assistant.unwrap_or(false),
+            };
+
+            let expanded = if req.expand.unwrap_or(false) {
+                crate::search::expand_query(&req.provider, &req.model, &query).await
+            } else {
+                vec![]
+            };
+            if !expanded.is_empty() {
+                yield ("expanded-queries", serde_json::json!({"queries": expanded}));
+            }
+            // Rephrasings run alongside the main search, sharing its provider slots; only the main one reports batches
+            let search_started = std::time::Instant::now();
+            let slots = crate::search::search_slots();
+            let expanded_searches = futures::future::join_all(expanded.iter().map(|q| crate::search::perform_search(
+                client.clone(), &state.limiter, &slots, providers_config.clone(), q.clone(), search_opts.clone(), None,
+            )));
+
             // Perform Search (returns empty vec if no providers selected)
-            let mut search_results = crate::search::perform_search(
+            // Each provider's new links go out as a `result-batch` the moment it finishes
+            let (batch_tx, mut batch_rx) = tokio::sync::mpsc::unbounded_channel();
+            let search = crate::search::perform_search(
                 client, 
+                &state.limiter,
+                &slots,
                 providers_config, 
-                req.query.clone(),
-                req.timeframe.clone()
-            ).await;
+                query.clone(),
+                search_opts,
+                Some(batch_tx)
+            );
+            let search = async { tokio::join!(search, expanded_searches) };
+            tokio::pin!(search);
+            let (outcome, expanded_outcomes) = loop {
+                tokio::select! {
+                    Some(batch) = batch_rx.recv() => {
+                        yield ("result-batch", serde_json::json!(batch));
+                    },
+                    outcome = &mut search => break outcome,
+                }
+            };
+            while let Ok(batch) = batch_rx.try_recv() {
+                yield ("result-batch", serde_json::json!(batch));
+            }
+            let statuses: Vec<_> = outcome.providers.iter().chain(expanded_outcomes.iter().flat_map(|o| &o.providers)).cloned().collect();
+            state.metrics.record_search(search_started.elapsed(), &statuses);
+            yield ("providers-status", serde_json::json!(outcome.providers));
+            let mut search_results = outcome.results;
+            if !expanded_outcomes.is_empty() {
+                // Round-robin so each phrasing's best hits survive truncation, first copy of a url wins
+                let mut lists: Vec<_> = std::iter::once(std::mem::take(&mut search_results))
+                    .chain(expanded_outcomes.into_iter().map(|o| o.results))
+                    .map(|l| l.into_iter())
+                    .collect();
+                let mut seen = std::collections::HashSet::new();
+                loop {
+                    let mut any = false;
+                    for list in lists.iter_mut() {
+                        if let Some(r) = list.next() {
+                            any = true;
+                            if seen.insert(r.url.clone()) { search_results.push(r); }
+                        }
+                    }
+                    if !any { break; }
+                }
+            }
+
+            if req.rerank.unwrap_or(false) && crate::search::rerank_configured() {
+                if let Err(e) = crate::search::rerank(&state.http, &query, &mut search_results).await {
+                    tracing::warn!("Reranking failed, keeping keyword order: {}", e);
+                }
+            }
 
-            if search_results.len() > 15 { search_results.truncate(15); }
+            let pinned = state.db.pinned_sources(conversation_id).unwrap_or_default();
+            let max_results = req.max_results.unwrap_or_else(default_max_results).clamp(1, MAX_RESULTS_CAP);
+            let mut search_results = with_pinned(search_results, pinned, max_results);
+
+            if log_query {
+                // Off the request path, a slow or locked DB must not hold up the answer
+                let state = state.clone();
+                let (query, timeframe, providers, count) = (query.clone(), req.timeframe.clone(), req.providers.clone(), search_results.len());
+                tokio::task::spawn_blocking(move || {
+                    if let Err(e) = state.db.log_query(&query, timeframe.as_deref(), providers.as_deref(), count) {
+                        tracing::warn!("Failed to log query: {}", e);
+                    }
+                });
+            }
 
             // Send results to UI (even if empty, so UI knows search finished)
-            yield Ok(Event::default().event("results").json_data(&search_results).unwrap());
+            yield ("results", serde_json::json!(search_results));
 
             let history = state.db.get_history(conversation_id).unwrap_or_default();
-            let current_date = chrono::Local::now().format("%Y-%m-%d").to_string();
-
-            // --- Prompt Logic ---
-            let user_prompt = if search_results.is_empty() {
-                // Pure Chat Mode (No Search Results)
-                format!(
-                    "Current Date: {}\nQuery: \"{}\"\n\nNo external search results were used for this response. Please answer the query using your internal knowledge.", 
-                    current_date, req.query
-                )
+            let snippet_max = if req.fetch_content.unwrap_or(false) {
+                crate::search::deep_read(&mut search_results).await;
+                crate::search::deep_read_max_chars()
             } else {
-                // RAG Mode (With Search Results)
-                let snippets: String = search_results.iter()
-                    .map(|r| format!("[{}] {}\nURL: {}\nSnippet: {}", r.engine, r.title, r.url, r.content))
-                    .collect::<Vec<_>>().join("\n\n---\n\n");
-                
-                format!(
-                    "Current Date: {}\nQuery: \"{}\"\n\nBased on the following search results, write a clear, concise summary answering the query. If results mention this date, they are current.\n\nSearch Results:\n{}", 
-                    current_date, req.query, snippets
-                )
+                default_snippet_max()
             };
-
-            yield Ok(Event::default().event("summary-start").data("{}"));
+            let user_prompt = build_user_prompt(&query, &search_results, snippet_max);
 
             let mut full_text = String::new();
-            let mut llm_stream = crate::llm::stream_completion(&req.provider, &req.model, &req.system_prompt, history, &user_prompt).await;
+            let mut llm = std::pin::pin!(llm_events(&state, &req, history, user_prompt, cancel.clone()));
+            while let Some((name, data)) = llm.next().await {
+                if name == "summary-chunk" { full_text.push_str(data["text"].as_str().unwrap_or("")); }
+                yield (name, data);
+            }
 
-            while let Some(chunk) = futures::StreamExt::next(&mut llm_stream).await {
-                match chunk {
-                    Ok(text) => {
-                        full_text.push_str(&text);
-                        yield Ok(Event::default().event("summary-chunk").json_data(serde_json::json!({"text": text})).unwrap());
-                    },
-                    Err(e) => {
-                        yield Ok(Event::default().event("error").json_data(serde_json::json!({"message": e.to_string()})).unwrap());
-                    }
+            if req.assess_confidence.unwrap_or(false) && !full_text.is_empty() && !cancel.is_cancelled() {
+                match assess_confidence(&req, &query, &full_text, &search_results).await {
+                    Ok(confidence) => yield ("confidence", confidence),
+                    Err(e) => tracing::warn!("Confidence assessment failed: {}", e),
                 }
             }
 
             let sources_json = serde_json::to_string(&search_results).unwrap_or_default();
             let msg_id = state.db.add_message(conversation_id, "assistant", &full_text, Some(&sources_json)).unwrap_or(0);
-            yield Ok(Event::default().event("summary-done").json_data(serde_json::json!({"messageId": msg_id})).unwrap());
+            if let Some(name) = &output_file {
+                match crate::export::write_query_output(name, &query, &full_text, &search_results) {
+                    Ok(path) => yield ("output-written", serde_json::json!({"path": path})),
+                    Err(e) => yield ("error", serde_json::json!({"message": e.to_string()})),
+                }
+            }
+            yield ("summary-done", serde_json::json!({"messageId": msg_id, "stopped": cancel.is_cancelled()}));
+        };
+
+        stream
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_state() -> Arc<AppState> {
+        let db = db::DbManager::new();
+        db.init_schema().unwrap();
+        Arc::new(AppState {
+            db,
+            http: reqwest::Client::new(),
+            active_queries: Mutex::new(HashMap::new()),
+            query_seq: Default::default(),
+            limiter: net::RateLimiter::from_env(),
+            metrics: metrics::Metrics::from_env(),
+        })
+    }
+
+    #[tokio::test]
+    async fn large_exports_are_gzipped() {
+        let state = test_state();
+        let conv_id = {
+            let conn = state.db.conn().unwrap();
+            conn.execute("INSERT INTO conversations (title) VALUES ('Big')", []).unwrap();
+            conn.last_insert_rowid()
         };
+        let answer = "A long researched answer. ".repeat(2000);
+        state.db.add_message(conv_id, "assistant", &answer, None).unwrap();
+        let addr = testutil::serve(app(state, CorsLayer::permissive())).await;
+
+        let resp = reqwest::Client::new()
+            .get(format!("http://{}/api/conversations/{}/export?format=md", addr, conv_id))
+            .header("Accept-Encoding", "gzip")
+            .send().await.unwrap();
+        assert!(resp.status().is_success());
+        assert_eq!(resp.headers().get("content-encoding").and_then(|v| v.to_str().ok()), Some("gzip"));
+        let compressed = resp.bytes().await.unwrap();
+        assert!(compressed.len() < answer.len() / 10, "{} bytes", compressed.len());
+    }
+
+    #[tokio::test]
+    async fn deleting_a_missing_provider_is_not_found() {
+        let addr = testutil::serve(app(test_state(), CorsLayer::permissive())).await;
+        let client = reqwest::Client::new();
+        let url = format!("http://{}/api/providers/999999", addr);
+        assert_eq!(client.delete(&url).send().await.unwrap().status(), reqwest::StatusCode::NOT_FOUND);
+    }
 
-        Sse::new(stream).keep_alive(KeepAlive::default())
+    fn result(url: &str, content: &str) -> search::SearchResult {
+        search::SearchResult { title: url.into(), url: url.into(), content: content.into(), ..Default::default() }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn pinned_sources_survive_truncation() {
+        let found = vec![result("https://a", "a"), result("https://pinned", "found snippet"), result("https://b", "b"), result("https://c", "c")];
+        let pinned = vec![result("https://pinned", ""), result("https://other-pin", "kept")];
+        let merged = handlers::with_pinned(found, pinned, 3);
+        let urls: Vec<_> = merged.iter().map(|r| r.url.as_str()).collect();
+        assert_eq!(urls, ["https://pinned", "https://other-pin", "https://a"]);
+        assert_eq!(merged[0].content, "found snippet");
+
+        let merged = handlers::with_pinned(vec![result("https://a", "a")], vec![result("https://p1", ""), result("https://p2", "")], 1);
+        assert_eq!(merged.len(), 2, "pins are never cut");
+    }
+}
@@ -0,0 +1,117 @@
+use axum::{extract::State, http::header, response::IntoResponse};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Upper bounds (seconds) shared by the latency histograms.
+const BUCKETS: [f64; 10] = [0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0];
+
+#[derive(Default)]
+struct Histogram {
+    counts: [u64; BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, secs: f64) {
+        for (i, le) in BUCKETS.iter().enumerate() {
+            if secs <= *le { self.counts[i] += 1; }
+        }
+        self.sum += secs;
+        self.count += 1;
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        out.push_str(&format!("# HELP {} {}\n# TYPE {} histogram\n", name, help, name));
+        for (le, count) in BUCKETS.iter().zip(self.counts) {
+            out.push_str(&format!("{}_bucket{{le=\"{}\"}} {}\n", name, le, count));
+        }
+        out.push_str(&format!("{}_bucket{{le=\"+Inf\"}} {}\n{}_sum {}\n{}_count {}\n", name, self.count, name, self.sum, name, self.count));
+    }
+}
+
+#[derive(Default)]
+struct Counters {
+    queries: u64,
+    /// `(provider name, success)` -> searches
+    provider_searches: BTreeMap<(String, bool), u64>,
+    prompt_tokens: u64,
+    completion_tokens: u64,
+    search_latency: Histogram,
+    llm_latency: Histogram,
+}
+
+/// In-process counters served at `GET /metrics` in Prometheus text format.
+/// Off unless `METRICS_ENABLED=1`; while off, recording is a no-op and the route isn't mounted.
+pub struct Metrics {
+    enabled: bool,
+    counters: Mutex<Counters>,
+}
+
+impl Metrics {
+    pub fn from_env() -> Self {
+        let enabled = std::env::var("METRICS_ENABLED").map(|v| v == "1" || v == "true").unwrap_or(false);
+        Self { enabled, counters: Mutex::new(Counters::default()) }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    fn update(&self, f: impl FnOnce(&mut Counters)) {
+        if self.enabled {
+            f(&mut self.counters.lock().unwrap_or_else(|e| e.into_inner()));
+        }
+    }
+
+    pub fn record_query(&self) {
+        self.update(|c| c.queries += 1);
+    }
+
+    /// The searches for one user query (rephrasings included) plus how each provider call fared.
+    pub fn record_search(&self, elapsed: Duration, providers: &[crate::search::ProviderStatus]) {
+        self.update(|c| {
+            c.search_latency.observe(elapsed.as_secs_f64());
+            for p in providers {
+                *c.provider_searches.entry((p.name.clone(), p.error.is_none())).or_default() += 1;
+            }
+        });
+    }
+
+    pub fn record_llm(&self, elapsed: Duration, usage: &crate::llm::Usage) {
+        self.update(|c| {
+            c.llm_latency.observe(elapsed.as_secs_f64());
+            c.prompt_tokens += usage.prompt_tokens;
+            c.completion_tokens += usage.completion_tokens;
+        });
+    }
+
+    fn render(&self) -> String {
+        let c = self.counters.lock().unwrap_or_else(|e| e.into_inner());
+        let mut out = String::new();
+        out.push_str("# HELP bplus_queries_total Queries answered.\n# TYPE bplus_queries_total counter\n");
+        out.push_str(&format!("bplus_queries_total {}\n", c.queries));
+        out.push_str("# HELP bplus_provider_searches_total Searches per provider by outcome.\n# TYPE bplus_provider_searches_total counter\n");
+        for ((provider, ok), count) in &c.provider_searches {
+            out.push_str(&format!(
+                "bplus_provider_searches_total{{provider=\"{}\",outcome=\"{}\"}} {}\n",
+                escape_label(provider), if *ok { "success" } else { "failure" }, count
+            ));
+        }
+        out.push_str("# HELP bplus_llm_tokens_total LLM tokens used, as reported or estimated.\n# TYPE bplus_llm_tokens_total counter\n");
+        out.push_str(&format!("bplus_llm_tokens_total{{type=\"prompt\"}} {}\n", c.prompt_tokens));
+        out.push_str(&format!("bplus_llm_tokens_total{{type=\"completion\"}} {}\n", c.completion_tokens));
+        c.search_latency.render(&mut out, "bplus_search_duration_seconds", "Time to run all providers for a search.");
+        c.llm_latency.render(&mut out, "bplus_llm_duration_seconds", "Time to stream an LLM answer.");
+        out
+    }
+}
+
+fn escape_label(v: &str) -> String {
+    v.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+pub async fn metrics(State(state): State<Arc<crate::AppState>>) -> impl IntoResponse {
+    ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], state.metrics.render())
+}
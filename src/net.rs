@@ -0,0 +1,287 @@
+use rand::Rng;
+use reqwest::redirect::{Attempt, Policy};
+use reqwest::{ClientBuilder, RequestBuilder, Response};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Redirect policy shared by every outbound client.
+/// `MAX_REDIRECTS` caps the chain (default 10) and `ALLOW_REDIRECT_DOWNGRADE=1`
+/// permits an https hop to be followed by a plain http one (blocked by default).
+pub fn redirect_policy() -> Policy {
+    let max = std::env::var("MAX_REDIRECTS").ok().and_then(|v| v.parse::<usize>().ok()).unwrap_or(10);
+    let allow_downgrade = std::env::var("ALLOW_REDIRECT_DOWNGRADE").map(|v| v == "1" || v == "true").unwrap_or(false);
+    policy(max, allow_downgrade)
+}
+
+fn policy(max: usize, allow_downgrade: bool) -> Policy {
+    Policy::custom(move |attempt: Attempt| {
+        if attempt.previous().len() > max {
+            attempt.error(format!("too many redirects (max {})", max))
+        } else if !allow_downgrade && is_downgrade(attempt.url(), attempt.previous()) {
+            attempt.error("refusing https -> http redirect downgrade")
+        } else {
+            attempt.follow()
+        }
+    })
+}
+
+fn is_downgrade(next: &reqwest::Url, previous: &[reqwest::Url]) -> bool {
+    next.scheme() == "http" && previous.iter().any(|u| u.scheme() == "https")
+}
+
+/// Base builder for outbound clients with the shared policy applied.
+pub fn client_builder() -> ClientBuilder {
+    reqwest::Client::builder().redirect(redirect_policy())
+}
+
+/// Whether `ip` is reachable on the public internet, i.e. not loopback, private,
+/// link-local, unspecified, CGNAT, multicast or their IPv6 (and v4-mapped) equivalents.
+pub fn is_public_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(v4) => {
+            let [a, b, ..] = v4.octets();
+            !(v4.is_loopback() || v4.is_private() || v4.is_link_local() || v4.is_unspecified()
+                || v4.is_broadcast() || v4.is_multicast() || v4.is_documentation()
+                || (a == 100 && (64..128).contains(&b)) || a == 0)
+        },
+        IpAddr::V6(v6) => {
+            if let Some(v4) = v6.to_ipv4_mapped() { return is_public_ip(IpAddr::V4(v4)); }
+            let first = v6.segments()[0];
+            !(v6.is_loopback() || v6.is_unspecified() || v6.is_multicast()
+                || (first & 0xfe00) == 0xfc00 // unique local
+                || (first & 0xffc0) == 0xfe80) // link-local
+        },
+    }
+}
+
+/// Resolves the host of `url` and checks that every address it maps to is public, so
+/// fetching an arbitrary (search result) URL can't be pointed at the local network.
+pub async fn is_public_url(url: &reqwest::Url) -> bool {
+    if !matches!(url.scheme(), "http" | "https") { return false; }
+    let port = url.port_or_known_default().unwrap_or(80);
+    let Some(host) = url.host_str() else { return false };
+    if let Ok(ip) = host.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+        return is_public_ip(ip);
+    }
+    match tokio::net::lookup_host((host, port)).await {
+        Ok(addrs) => {
+            let addrs: Vec<_> = addrs.collect();
+            !addrs.is_empty() && addrs.iter().all(|a| is_public_ip(a.ip()))
+        },
+        Err(_) => false,
+    }
+}
+
+const DEFAULT_USER_AGENT: &str = "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/120.0.0.0 Safari/537.36";
+
+/// User-Agent for outbound search requests: a random pick from `SEARCH_USER_AGENTS`
+/// (comma-separated) when set, else `SEARCH_USER_AGENT`, else a desktop Chrome string.
+pub fn user_agent() -> String {
+    let rotation: Vec<String> = std::env::var("SEARCH_USER_AGENTS").unwrap_or_default()
+        .split(',').map(str::trim).filter(|ua| !ua.is_empty()).map(String::from).collect();
+    if !rotation.is_empty() {
+        return rotation[rand::thread_rng().gen_range(0..rotation.len())].clone();
+    }
+    std::env::var("SEARCH_USER_AGENT").ok().filter(|ua| !ua.trim().is_empty())
+        .unwrap_or_else(|| DEFAULT_USER_AGENT.to_string())
+}
+
+/// Per-host token bucket: each host refills at `rps` tokens per second up to a burst of
+/// `max(rps, 1)`. Callers that find the bucket empty reserve the next token and sleep.
+pub struct RateLimiter {
+    rps: f64,
+    buckets: Mutex<HashMap<String, (f64, Instant)>>,
+}
+
+impl RateLimiter {
+    /// Rate from `SCRAPER_RPS` (default 1 request per second per host).
+    pub fn from_env() -> Self {
+        let rps = std::env::var("SCRAPER_RPS").ok().and_then(|v| v.parse::<f64>().ok()).filter(|r| *r > 0.0).unwrap_or(1.0);
+        Self { rps, buckets: Mutex::new(HashMap::new()) }
+    }
+
+    /// Waits for a token for `host`. Gives up without taking one (returns false) when
+    /// the wait would exceed `max_wait`.
+    pub async fn acquire(&self, host: &str, max_wait: Duration) -> bool {
+        let wait = {
+            let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+            let capacity = self.rps.max(1.0);
+            let now = Instant::now();
+            let (tokens, last) = buckets.entry(host.to_string()).or_insert((capacity, now));
+            *tokens = (*tokens + now.duration_since(*last).as_secs_f64() * self.rps).min(capacity);
+            *last = now;
+            // Tokens go negative for callers already queued, so each one waits its turn
+            let wait = Duration::from_secs_f64(((1.0 - *tokens) / self.rps).max(0.0));
+            if wait > max_wait { return false; }
+            *tokens -= 1.0;
+            wait
+        };
+        if !wait.is_zero() {
+            tracing::debug!(host, wait_ms = wait.as_millis() as u64, "rate limited");
+            tokio::time::sleep(wait).await;
+        }
+        true
+    }
+}
+
+/// Sends `req`, retrying timeouts, connection errors and 5xx responses up to
+/// `SEARCH_MAX_RETRIES` times (default 2) with exponential backoff plus jitter.
+/// 4xx responses come straight back, retrying won't change the answer.
+pub async fn send_with_retry(req: RequestBuilder) -> reqwest::Result<Response> {
+    let max_retries = std::env::var("SEARCH_MAX_RETRIES").ok().and_then(|v| v.parse::<u32>().ok()).unwrap_or(2);
+    let mut attempt = 0;
+    loop {
+        // Bodies that can't be cloned (streams) only get one shot
+        let Some(this_try) = req.try_clone() else { return req.send().await };
+        match this_try.send().await {
+            Ok(resp) if resp.status().is_server_error() && attempt < max_retries => {
+                tracing::debug!(status = %resp.status(), attempt, "retrying after server error");
+            },
+            Ok(resp) => return Ok(resp),
+            Err(e) if (e.is_timeout() || e.is_connect() || e.is_request()) && attempt < max_retries => {
+                tracing::debug!(attempt, "retrying after request error: {}", e);
+            },
+            Err(e) => return Err(e),
+        }
+        let backoff = 250u64 * 2u64.pow(attempt);
+        let jitter = rand::thread_rng().gen_range(0..=backoff / 2);
+        tokio::time::sleep(Duration::from_millis(backoff + jitter)).await;
+        attempt += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{extract::Path, response::Redirect, routing::get, Router};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    fn client(max: usize, allow_downgrade: bool) -> reqwest::Client {
+        reqwest::Client::builder()
+            .redirect(policy(max, allow_downgrade))
+            .danger_accept_invalid_certs(true)
+            .build()
+            .unwrap()
+    }
+
+    async fn redirect_server(hits: Arc<AtomicUsize>) -> std::net::SocketAddr {
+        crate::testutil::serve(Router::new()
+            .route("/loop", get(move || {
+                hits.fetch_add(1, Ordering::SeqCst);
+                async { Redirect::temporary("/loop") }
+            }))
+            .route("/hop/:n", get(|Path(n): Path<u32>| async move {
+                if n == 0 { Redirect::temporary("/ok") } else { Redirect::temporary(&format!("/hop/{}", n - 1)) }
+            }))
+            .route("/ok", get(|| async { "ok" }))
+        ).await
+    }
+
+    #[tokio::test]
+    async fn redirect_loop_is_bounded() {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let addr = redirect_server(hits.clone()).await;
+        let err = client(3, false).get(format!("http://{}/loop", addr)).send().await.unwrap_err();
+        assert!(err.is_redirect(), "{}", err);
+        // The first request plus three followed redirects
+        assert_eq!(hits.load(Ordering::SeqCst), 4);
+    }
+
+    #[tokio::test]
+    async fn redirect_chain_within_cap_is_followed() {
+        let addr = redirect_server(Arc::default()).await;
+        // /hop/2 -> /hop/1 -> /hop/0 -> /ok is three redirects
+        let resp = client(3, false).get(format!("http://{}/hop/2", addr)).send().await.unwrap();
+        assert_eq!(resp.text().await.unwrap(), "ok");
+        let err = client(2, false).get(format!("http://{}/hop/2", addr)).send().await.unwrap_err();
+        assert!(err.is_redirect(), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn https_to_http_downgrade_needs_opt_in() {
+        let plain = redirect_server(Arc::default()).await;
+        let tls = crate::testutil::serve_tls_redirect(format!("http://{}/ok", plain));
+        let url = format!("https://localhost:{}/", tls.port());
+
+        let err = client(10, false).get(&url).send().await.unwrap_err();
+        assert!(err.is_redirect(), "{}", err);
+        assert!(format!("{:?}", err).contains("downgrade"), "{:?}", err);
+
+        let resp = client(10, true).get(&url).send().await.unwrap();
+        assert_eq!(resp.text().await.unwrap(), "ok");
+    }
+
+    /// Answers with `status` until `failures` requests have been made, then `200 ok`.
+    async fn flaky_server(failures: usize, status: axum::http::StatusCode, hits: Arc<AtomicUsize>) -> std::net::SocketAddr {
+        crate::testutil::serve(Router::new().route("/", get(move || {
+            let n = hits.fetch_add(1, Ordering::SeqCst);
+            async move { if n < failures { (status, "down") } else { (axum::http::StatusCode::OK, "ok") } }
+        }))).await
+    }
+
+    #[tokio::test]
+    async fn retries_server_error_once_then_succeeds() {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let addr = flaky_server(1, axum::http::StatusCode::INTERNAL_SERVER_ERROR, hits.clone()).await;
+        let resp = send_with_retry(reqwest::Client::new().get(format!("http://{}/", addr))).await.unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::OK);
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn client_errors_are_not_retried() {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let addr = flaky_server(1, axum::http::StatusCode::TOO_MANY_REQUESTS, hits.clone()).await;
+        let resp = send_with_retry(reqwest::Client::new().get(format!("http://{}/", addr))).await.unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn downgrade_only_after_an_https_hop() {
+        let url = |u: &str| reqwest::Url::parse(u).unwrap();
+        assert!(is_downgrade(&url("http://b/"), &[url("http://a/"), url("https://a/")]));
+        assert!(!is_downgrade(&url("https://b/"), &[url("https://a/")]));
+        assert!(!is_downgrade(&url("http://b/"), &[url("http://a/")]));
+    }
+
+    #[test]
+    fn private_and_local_addresses_are_not_public() {
+        for ip in ["127.0.0.1", "10.1.2.3", "172.16.0.1", "192.168.1.1", "169.254.169.254", "100.64.0.1", "0.0.0.0", "::1", "fe80::1", "fd00::1", "::ffff:127.0.0.1"] {
+            assert!(!is_public_ip(ip.parse().unwrap()), "{} should not be public", ip);
+        }
+        for ip in ["1.1.1.1", "93.184.216.34", "2606:4700:4700::1111"] {
+            assert!(is_public_ip(ip.parse().unwrap()), "{} should be public", ip);
+        }
+    }
+
+    #[tokio::test]
+    async fn local_urls_are_not_public() {
+        for url in ["http://localhost:8080/", "http://127.0.0.1/", "http://[::1]/", "http://169.254.169.254/latest/meta-data", "file:///etc/passwd"] {
+            assert!(!is_public_url(&reqwest::Url::parse(url).unwrap()).await, "{} should be rejected", url);
+        }
+        assert!(is_public_url(&reqwest::Url::parse("https://1.1.1.1/").unwrap()).await);
+    }
+
+    #[tokio::test]
+    async fn rapid_requests_to_one_host_are_spaced_out() {
+        let limiter = RateLimiter { rps: 1.0, buckets: Mutex::new(HashMap::new()) };
+        let started = Instant::now();
+        assert!(limiter.acquire("duckduckgo.com", Duration::from_secs(5)).await);
+        assert!(limiter.acquire("www.qwant.com", Duration::from_secs(5)).await);
+        assert!(started.elapsed() < Duration::from_millis(200), "different hosts shouldn't wait");
+
+        assert!(limiter.acquire("duckduckgo.com", Duration::from_secs(5)).await);
+        assert!(started.elapsed() >= Duration::from_millis(900), "second request went out after {:?}", started.elapsed());
+    }
+
+    #[tokio::test]
+    async fn limiter_gives_up_when_the_wait_is_too_long() {
+        let limiter = RateLimiter { rps: 0.1, buckets: Mutex::new(HashMap::new()) };
+        assert!(limiter.acquire("www.mojeek.com", Duration::from_secs(1)).await);
+        assert!(!limiter.acquire("www.mojeek.com", Duration::from_secs(1)).await);
+    }
+}
@@ -0,0 +1,139 @@
+use crate::llm::{Chunk, Message, Usage};
+use axum::{
+    extract::State,
+    response::{sse::{Event, KeepAlive}, IntoResponse, Response, Sse},
+    Json,
+};
+use futures::StreamExt;
+use serde::Deserialize;
+use std::sync::Arc;
+
+#[derive(Deserialize)]
+pub struct ChatCompletionRequest {
+    model: String,
+    messages: Vec<ChatMessage>,
+    #[serde(default)]
+    stream: bool,
+    /// Non-standard: which LLM backend to use, defaults to `PROXY_LLM_PROVIDER` or `lmstudio`
+    provider: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ChatMessage {
+    role: String,
+    #[serde(default)]
+    content: serde_json::Value,
+}
+
+impl ChatMessage {
+    // Content is either a plain string or an array of typed parts
+    fn text(&self) -> String {
+        match &self.content {
+            serde_json::Value::String(s) => s.clone(),
+            serde_json::Value::Array(parts) => parts.iter()
+                .filter_map(|p| p["text"].as_str())
+                .collect::<Vec<_>>()
+                .join("\n"),
+            _ => String::new(),
+        }
+    }
+}
+
+fn completion_chunk(id: &str, created: i64, model: &str, delta: serde_json::Value, finish: Option<&str>) -> serde_json::Value {
+    serde_json::json!({
+        "id": id,
+        "object": "chat.completion.chunk",
+        "created": created,
+        "model": model,
+        "choices": [{ "index": 0, "delta": delta, "finish_reason": finish }]
+    })
+}
+
+/// `POST /v1/chat/completions`: OpenAI-shaped chat where the last user message is
+/// searched with the enabled providers and the results are injected as context.
+pub async fn chat_completions(State(state): State<Arc<crate::AppState>>, Json(req): Json<ChatCompletionRequest>) -> Response {
+    let provider = req.provider.clone()
+        .or_else(|| std::env::var("PROXY_LLM_PROVIDER").ok())
+        .unwrap_or_else(|| "lmstudio".into());
+
+    let system_prompt = req.messages.iter().filter(|m| m.role == "system" || m.role == "developer")
+        .map(|m| m.text()).collect::<Vec<_>>().join("\n\n");
+    let Some(last_user) = req.messages.iter().rposition(|m| m.role == "user") else {
+        return (axum::http::StatusCode::BAD_REQUEST, Json(serde_json::json!({
+            "error": { "message": "messages must contain a user message", "type": "invalid_request_error" }
+        }))).into_response();
+    };
+    let query = req.messages[last_user].text();
+    let history: Vec<Message> = req.messages[..last_user].iter()
+        .filter(|m| m.role == "user" || m.role == "assistant")
+        .map(|m| Message { role: m.role.clone(), content: m.text() })
+        .collect();
+
+    let providers = state.db.get_providers(None).unwrap_or_default()
+        .into_iter().filter(|p| p.is_enabled).collect();
+    let opts = crate::search::SearchOptions { local_db_files: state.db.local_search_scope(), ..Default::default() };
+    let started = std::time::Instant::now();
+    let outcome = crate::search::perform_search(state.http.clone(), &state.limiter, &crate::search::search_slots(), providers, query.clone(), opts, None).await;
+    state.metrics.record_search(started.elapsed(), &outcome.providers);
+    let mut results = outcome.results;
+    results.truncate(crate::handlers::default_max_results());
+    let user_prompt = crate::handlers::build_user_prompt(&query, &results, crate::handlers::default_snippet_max());
+
+    let id = format!("chatcmpl-{}", chrono::Utc::now().timestamp_millis());
+    let created = chrono::Utc::now().timestamp();
+    let model = req.model.clone();
+    let prompt_chars = system_prompt.len() + user_prompt.len() + history.iter().map(|m| m.content.len()).sum::<usize>();
+    let mut llm_stream = crate::llm::stream_completion(&provider, &req.model, &[], &system_prompt, history, &user_prompt).await;
+
+    if req.stream {
+        let stream = async_stream::stream! {
+            yield Ok::<_, axum::BoxError>(Event::default().json_data(completion_chunk(&id, created, &model, serde_json::json!({"role": "assistant"}), None)).unwrap());
+            while let Some(chunk) = llm_stream.next().await {
+                match chunk {
+                    Ok(Chunk::Text(text)) => {
+                        yield Ok(Event::default().json_data(completion_chunk(&id, created, &model, serde_json::json!({"content": text}), None)).unwrap());
+                    },
+                    Ok(Chunk::Reasoning(text)) => {
+                        yield Ok(Event::default().json_data(completion_chunk(&id, created, &model, serde_json::json!({"reasoning_content": text}), None)).unwrap());
+                    },
+                    Ok(_) => {},
+                    Err(e) => {
+                        yield Ok(Event::default().json_data(serde_json::json!({"error": {"message": e.to_string()}})).unwrap());
+                        break;
+                    }
+                }
+            }
+            yield Ok(Event::default().json_data(completion_chunk(&id, created, &model, serde_json::json!({}), Some("stop"))).unwrap());
+            yield Ok(Event::default().data("[DONE]"));
+        };
+        return Sse::new(stream).keep_alive(KeepAlive::default()).into_response();
+    }
+
+    let mut content = String::new();
+    let mut usage = None;
+    while let Some(chunk) = llm_stream.next().await {
+        match chunk {
+            Ok(Chunk::Text(text)) => content.push_str(&text),
+            Ok(Chunk::Usage(u)) => usage = Some(u),
+            Ok(Chunk::Reasoning(_) | Chunk::Fallback { .. }) => {},
+            Err(e) => {
+                return (axum::http::StatusCode::BAD_GATEWAY, Json(serde_json::json!({
+                    "error": { "message": e.to_string(), "type": "upstream_error" }
+                }))).into_response();
+            }
+        }
+    }
+    let usage = usage.unwrap_or_else(|| Usage::estimate(prompt_chars, content.len()));
+    Json(serde_json::json!({
+        "id": id,
+        "object": "chat.completion",
+        "created": created,
+        "model": model,
+        "choices": [{
+            "index": 0,
+            "message": { "role": "assistant", "content": content },
+            "finish_reason": "stop"
+        }],
+        "usage": usage
+    })).into_response()
+}
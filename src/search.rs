@@ -1,23 +1,28 @@
 use serde::{Deserialize, Serialize};
 use scraper::{Html, Selector};
-use axum::{Json, extract::Query};
-use futures::future::join_all;
+use axum::{Json, extract::{Query, State}};
+use futures::{stream, StreamExt};
+use tracing::Instrument;
 use reqwest::Client;
 use std::pin::Pin;
 use std::future::Future;
 use std::collections::HashSet;
 use std::path::PathBuf;
+use std::sync::Arc;
 use rusqlite::{Connection, OpenFlags, params};
 
-#[derive(Serialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
 pub struct SearchResult {
     pub title: String,
     pub url: String,
     pub content: String,
     pub engine: String,
+    /// Publication date as RFC 3339, when the provider exposes one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub date: Option<String>,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
 pub struct ProviderConfig {
     pub id: i64,
     pub name: String,
@@ -30,10 +35,116 @@ pub struct ProviderConfig {
     pub url_path: Option<String>,
     pub content_path: Option<String>,
     pub is_enabled: bool, 
+    pub transform: Option<String>,
+    /// Comma-separated content paths tried in order when `content_path` resolves empty.
+    pub content_fallbacks: Option<String>,
+    /// Path to a publication date (unix timestamp or ISO 8601 string).
+    pub date_path: Option<String>,
+    /// Higher runs first and wins ties in the final ranking (default 0).
+    pub priority: i64,
+    /// Gives up on this provider after this long, counting it as failed with no results.
+    pub timeout_ms: Option<i64>,
+    /// Keeps at most this many of the provider's results.
+    pub max_results: Option<i64>,
+    /// Set when secret header values were masked for display; such a config must
+    /// never be written back (`add_provider` rejects it) or used to search (the generic
+    /// provider refuses it).
+    #[serde(default)]
+    pub redacted: bool,
+}
+
+impl ProviderConfig {
+    /// Copy safe to hand to clients: credential-looking header values become `***`.
+    pub fn redacted(mut self) -> Self {
+        if let Some(raw) = self.api_headers.as_deref().filter(|h| !h.trim().is_empty()) {
+            self.api_headers = Some(match serde_json::from_str::<std::collections::HashMap<String, String>>(raw) {
+                Ok(mut headers) => {
+                    for (k, v) in headers.iter_mut() {
+                        if is_secret_header(k) {
+                            *v = if v.starts_with("Bearer ") { "Bearer ***".into() } else { "***".into() };
+                        } else if v.starts_with("Bearer ") {
+                            *v = "Bearer ***".into();
+                        }
+                    }
+                    serde_json::to_string(&headers).unwrap_or_else(|_| "***".into())
+                },
+                // Can't tell what's in there, hide all of it
+                Err(_) => "***".into(),
+            });
+        }
+        self.redacted = true;
+        self
+    }
+}
+
+/// A header value as `redacted()` leaves it.
+pub fn is_masked_value(v: &str) -> bool {
+    v == "***" || v == "Bearer ***"
+}
+
+/// Whether stored-format `api_headers` still contain masked values, i.e. came from a listing.
+pub fn has_masked_headers(raw: &str) -> bool {
+    is_masked_value(raw.trim())
+        || serde_json::from_str::<std::collections::HashMap<String, String>>(raw).is_ok_and(|h| h.values().any(|v| is_masked_value(v)))
+}
+
+fn is_secret_header(name: &str) -> bool {
+    let name = name.to_ascii_lowercase();
+    name == "authorization" || name.contains("key") || name.contains("token") || name.contains("secret")
+}
+
+/// Per-query knobs handed to every provider; each one uses what its engine supports.
+#[derive(Clone, Debug, Default)]
+pub struct SearchOptions {
+    /// `day`, `week` or `month`
+    pub timeframe: Option<String>,
+    /// Language code such as `de` or `pt-BR`
+    pub lang: Option<String>,
+    /// Country code such as `de` or `us`
+    pub region: Option<String>,
+    /// When non-empty, only results from these domains (or their subdomains) are kept
+    pub include_domains: Vec<String>,
+    /// Results from these domains (or their subdomains) are dropped
+    pub exclude_domains: Vec<String>,
+    /// `.db` files the Local Database provider may read; `None` scans the working directory
+    pub local_db_files: Option<Vec<PathBuf>>,
+    /// Restricts Local Database message hits to `assistant` or `user`
+    pub local_db_role: Option<String>,
+    /// Lets assistant answers win the Local Database's one-hit-per-conversation pick
+    pub local_db_prefer_assistant: bool,
+}
+
+impl SearchOptions {
+    /// Drops `lang`/`region` values that aren't plain codes, they end up in hostnames and URLs.
+    fn sanitized(mut self) -> Self {
+        let code = |v: Option<String>| v.map(|v| v.trim().to_string())
+            .filter(|v| !v.is_empty() && v.len() <= 12 && v.chars().all(|c| c.is_ascii_alphanumeric() || c == '-'));
+        self.lang = code(self.lang);
+        self.region = code(self.region);
+        let domains = |v: Vec<String>| v.into_iter()
+            .map(|d| d.trim().trim_start_matches("*.").trim_matches('.').to_lowercase())
+            .filter(|d| !d.is_empty())
+            .collect();
+        self.include_domains = domains(self.include_domains);
+        self.exclude_domains = domains(self.exclude_domains);
+        self.local_db_role = self.local_db_role.filter(|r| r == "assistant" || r == "user");
+        self
+    }
+
+    /// Applies the include/exclude domain lists to a result URL. URLs without a host
+    /// only pass when there is no include list.
+    fn allows_url(&self, url: &str) -> bool {
+        if self.include_domains.is_empty() && self.exclude_domains.is_empty() { return true; }
+        let host = reqwest::Url::parse(url).ok().and_then(|u| u.host_str().map(str::to_lowercase));
+        let matches = |domains: &[String]| host.as_deref().is_some_and(|h| {
+            domains.iter().any(|d| h == d || h.strip_suffix(d.as_str()).is_some_and(|rest| rest.ends_with('.')))
+        });
+        (self.include_domains.is_empty() || matches(&self.include_domains)) && !matches(&self.exclude_domains)
+    }
 }
 
 pub trait SearchProvider: Send + Sync {
-    fn search(&self, client: Client, query: String, timeframe: Option<String>) -> Pin<Box<dyn Future<Output = Vec<SearchResult>> + Send>>;
+    fn search(&self, client: Client, query: String, opts: SearchOptions) -> Pin<Box<dyn Future<Output = anyhow::Result<Vec<SearchResult>>> + Send>>;
 }
 
 // 1. Generic API Provider
@@ -48,7 +159,7 @@ impl GenericApiProvider {
         let mut curr = val;
         
         for part in parts {
-            if let Some(idx) = part.parse::<usize>().ok() {
+            if let Ok(idx) = part.parse::<usize>() {
                 if let Some(arr) = curr.as_array() { 
                     if idx < arr.len() { curr = &arr[idx]; } else { return "".to_string(); }
                 } else { return "".to_string(); }
@@ -67,21 +178,34 @@ impl GenericApiProvider {
 }
 
 impl GenericApiProvider {
+    fn extract_content(&self, val: &serde_json::Value) -> String {
+        let primary = self.extract(val, self.config.content_path.as_ref());
+        if !primary.is_empty() { return primary; }
+        self.config.content_fallbacks.as_deref().unwrap_or("")
+            .split(',')
+            .map(|p| p.trim().to_string())
+            .filter(|p| !p.is_empty())
+            .map(|p| self.extract(val, Some(&p)))
+            .find(|c| !c.is_empty())
+            .unwrap_or_default()
+    }
+
     pub fn new(config: ProviderConfig) -> Self {
         Self { config }
     }
 }
 
 impl SearchProvider for GenericApiProvider {
-    fn search(&self, client: Client, query: String, _timeframe: Option<String>) -> Pin<Box<dyn Future<Output = Vec<SearchResult>> + Send>> {
+    fn search(&self, client: Client, query: String, _opts: SearchOptions) -> Pin<Box<dyn Future<Output = anyhow::Result<Vec<SearchResult>>> + Send>> {
         let config = self.config.clone();
         Box::pin(async move {
+            // Masked headers would go out as literal `***` credentials
+            if config.redacted { anyhow::bail!("refusing to search with a redacted provider config"); }
             let url_tmpl = config.api_url.as_deref().unwrap_or("");
-            if url_tmpl.is_empty() { return vec![]; }
+            if url_tmpl.is_empty() { anyhow::bail!("no API URL configured"); }
             let url = url_tmpl.replace("{q}", &urlencoding::encode(&query));
             
-            let mut req = client.get(&url);
-            req = req.header("User-Agent", "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/120.0.0.0 Safari/537.36");
+            let mut req = get(&client, &url);
 
             if let Some(h_str) = &config.api_headers {
                 if let Ok(headers) = serde_json::from_str::<std::collections::HashMap<String, String>>(h_str) {
@@ -89,37 +213,68 @@ impl SearchProvider for GenericApiProvider {
                 }
             }
 
-            let mut results = Vec::new();
-            match req.send().await {
-                Ok(resp) => {
-                    if let Ok(json) = resp.json::<serde_json::Value>().await {
-                        let mut root = &json;
-                        if let Some(rpath) = &config.result_path {
-                            for part in rpath.split('.') {
-                                if !part.is_empty() { root = &root[part]; }
-                            }
-                        }
-                        
-                        if let Some(arr) = root.as_array() {
-                            for item in arr {
-                                let title = GenericApiProvider::new(config.clone()).extract(item, config.title_path.as_ref());
-                                let url = GenericApiProvider::new(config.clone()).extract(item, config.url_path.as_ref());
-                                
-                                if !url.is_empty() {
-                                    results.push(SearchResult {
-                                        title: if title.is_empty() { "No Title".into() } else { title },
-                                        url,
-                                        content: GenericApiProvider::new(config.clone()).extract(item, config.content_path.as_ref()),
-                                        engine: config.name.clone()
-                                    });
-                                }
-                            }
-                        }
-                    }
+            let transform = match config.transform.as_deref().map(str::trim) {
+                Some(t) if !t.is_empty() => match crate::transform::Transform::parse(t) {
+                    Ok(t) => Some(t),
+                    Err(e) => { tracing::warn!(provider = %config.name, "Invalid transform: {}", e); None }
                 },
-                Err(e) => println!("Error: Request failed: {}", e),
+                _ => None,
+            };
+
+            let resp = match crate::net::send_with_retry(req).await {
+                Ok(r) if r.status().is_success() => r,
+                Ok(r) => {
+                    tracing::warn!(provider = %config.name, status = %r.status(), "Request rejected");
+                    anyhow::bail!("HTTP {}", r.status());
+                },
+                Err(e) => {
+                    tracing::warn!(provider = %config.name, "Request failed: {}", e);
+                    return Err(e.into());
+                },
+            };
+            let json = resp.json::<serde_json::Value>().await
+                .map_err(|e| anyhow::anyhow!("response is not JSON: {}", e))?;
+
+            let mut root = &json;
+            if let Some(rpath) = &config.result_path {
+                for part in rpath.split('.') {
+                    if !part.is_empty() { root = &root[part]; }
+                }
+            }
+
+            // Usually an array, but some APIs return an object keyed by id
+            let items: Vec<&serde_json::Value> = match root {
+                serde_json::Value::Array(arr) => arr.iter().collect(),
+                serde_json::Value::Object(map) => map.values().collect(),
+                _ => vec![],
+            };
+
+            let mut results = Vec::new();
+            let extractor = GenericApiProvider::new(config.clone());
+            for item in items {
+                let mut title = extractor.extract(item, config.title_path.as_ref());
+                let mut url = extractor.extract(item, config.url_path.as_ref());
+                let mut content = extractor.extract_content(item);
+
+                if let Some(t) = &transform {
+                    let mut fields = std::collections::HashMap::from([("title", title), ("url", url), ("content", content)]);
+                    t.apply(&mut fields, &|path| extractor.extract(item, Some(&path.to_string())));
+                    title = fields.remove("title").unwrap_or_default();
+                    url = fields.remove("url").unwrap_or_default();
+                    content = fields.remove("content").unwrap_or_default();
+                }
+
+                if !url.is_empty() {
+                    results.push(SearchResult {
+                        title: if title.is_empty() { "No Title".into() } else { title },
+                        url,
+                        content,
+                        engine: config.name.clone(),
+                        date: iso_date(&extractor.extract(item, config.date_path.as_ref())),
+                    });
+                }
             }
-            results
+            Ok(results)
         })
     }
 }
@@ -131,30 +286,93 @@ struct NativeProvider {
 }
 
 impl SearchProvider for NativeProvider {
-    fn search(&self, client: Client, query: String, timeframe: Option<String>) -> Pin<Box<dyn Future<Output = Vec<SearchResult>> + Send>> {
+    fn search(&self, client: Client, query: String, opts: SearchOptions) -> Pin<Box<dyn Future<Output = anyhow::Result<Vec<SearchResult>>> + Send>> {
         let id = self.id.clone();
+        let SearchOptions { timeframe, lang, region, local_db_files, local_db_role, local_db_prefer_assistant, .. } = opts;
         Box::pin(async move {
             match id.as_str() {
-                "native_local_db" => local_db_search(query).await,
-                "native_ddg" => ddg_web(client, query, timeframe).await,
+                "native_local_db" => local_db_search(query, local_db_files, local_db_role, local_db_prefer_assistant).await,
+                "native_ddg" => ddg_web(client, query, timeframe, lang, region).await,
                 "native_qwant" => qwant_web(client, query).await,
                 "native_mojeek" => mojeek_web(client, query).await,
-                "native_wiki" => wikipedia_web(client, query).await,
-                "native_reddit" => reddit_web(client, query).await,
-                "native_stack" => stackexchange_web(client, query).await,
-                "native_searxng" => searxng_search(client, query, timeframe).await,
-                _ => vec![]
+                "native_ecosia" => ecosia_web(client, query).await,
+                "native_wiki" => wikipedia_web(client, query, lang).await,
+                "native_reddit" => reddit_web(client, query, timeframe).await,
+                "native_stack" => stackexchange_web(client, query, timeframe).await,
+                "native_github" => github_web(client, query).await,
+                "native_youtube" => youtube_search(client, query).await,
+                "native_marginalia" => marginalia_search(client, query).await,
+                "native_wayback" => archive_org_search(client, query).await,
+                "native_lemmy" => lemmy_search(client, query).await,
+                "native_semanticscholar" => semantic_scholar_search(client, query).await,
+                "native_pubmed" => pubmed_search(client, query).await,
+                "native_googlenews" => google_news_search(client, query, timeframe, lang, region).await,
+                "native_searxng" => searxng_search(client, query, timeframe, lang, region).await,
+                other => anyhow::bail!("unknown native provider '{}'", other),
             }
         })
     }
 }
 
+/// How one provider did in a search, reported to the UI as `providers-status`.
+#[derive(Serialize, Clone, Debug)]
+pub struct ProviderStatus {
+    pub name: String,
+    pub count: usize,
+    pub duration_ms: u64,
+    pub error: Option<String>,
+}
+
+/// Results one provider added that earlier providers hadn't, sent as soon as it finishes.
+#[derive(Serialize, Clone, Debug)]
+pub struct ResultBatch {
+    pub provider: String,
+    pub results: Vec<SearchResult>,
+}
+
+pub struct SearchOutcome {
+    /// Merged, deduped and ranked results
+    pub results: Vec<SearchResult>,
+    /// One entry per provider queried, in provider order
+    pub providers: Vec<ProviderStatus>,
+}
+
+/// Host behind each HTML scraper, the engines that block IPs for hammering them.
+fn scraper_host(native_id: &str) -> Option<&'static str> {
+    match native_id {
+        "native_ddg" => Some("duckduckgo.com"),
+        "native_qwant" => Some("www.qwant.com"),
+        "native_mojeek" => Some("www.mojeek.com"),
+        "native_ecosia" => Some("www.ecosia.org"),
+        _ => None,
+    }
+}
+
+/// Longest a scraper waits for the rate limiter, matching the HTTP client timeout.
+const MAX_RATE_LIMIT_WAIT: std::time::Duration = std::time::Duration::from_secs(15);
+
+/// Provider slots for one user query (`SEARCH_CONCURRENCY`, default 6), shared by every
+/// `perform_search` that query runs so rephrasings don't multiply the load.
+pub fn search_slots() -> tokio::sync::Semaphore {
+    let concurrency = std::env::var("SEARCH_CONCURRENCY").ok().and_then(|v| v.parse::<usize>().ok()).filter(|n| *n > 0).unwrap_or(6);
+    tokio::sync::Semaphore::new(concurrency)
+}
+
+/// Runs `query` against `providers`, at most as many at once as `slots` has permits.
+/// Callers record the run in the metrics, once per user query.
+#[tracing::instrument(skip_all, fields(query = %query, providers = providers.len()))]
 pub async fn perform_search(
     client: Client, 
+    limiter: &crate::net::RateLimiter,
+    slots: &tokio::sync::Semaphore,
     providers: Vec<ProviderConfig>, 
     query: String,
-    timeframe: Option<String>
-) -> Vec<SearchResult> {
+    opts: SearchOptions,
+    progress: Option<tokio::sync::mpsc::UnboundedSender<ResultBatch>>,
+) -> SearchOutcome {
+    let started = std::time::Instant::now();
+    let opts = opts.sanitized();
+    let timeframe = opts.timeframe.clone();
     let mut futures = Vec::new();
     
     // Default to Local Database if no providers selected
@@ -165,15 +383,23 @@ pub async fn perform_search(
                 name: "Local Database".into(), 
                 type_: "native".into(), 
                 api_url: Some("native_local_db".into()), 
-                api_headers: None, result_path: None, title_path: None, url_path: None, content_path: None,
-                is_enabled: true 
+                is_enabled: true,
+                ..Default::default()
             },
         ]
     } else {
+        let mut providers = providers;
+        // Provider order decides which duplicate survives and breaks ranking ties (the sort below is stable)
+        providers.sort_by_key(|p| std::cmp::Reverse(p.priority));
         providers
     };
 
     for p in effective_providers {
+        let span = tracing::info_span!("provider", name = %p.name);
+        let name = p.name.clone();
+        let host = if p.type_ == "generic" { None } else { p.api_url.as_deref().and_then(scraper_host) };
+        let budget = p.timeout_ms.filter(|ms| *ms > 0).map(|ms| std::time::Duration::from_millis(ms as u64));
+        let cap = p.max_results.filter(|n| *n > 0).map(|n| n as usize);
         let provider: Box<dyn SearchProvider> = if p.type_ == "generic" {
             Box::new(GenericApiProvider { config: p })
         } else {
@@ -182,12 +408,66 @@ pub async fn perform_search(
                 _name: p.name.clone() 
             })
         };
-        futures.push(provider.search(client.clone(), query.clone(), timeframe.clone()));
+        let search = provider.search(client.clone(), query.clone(), opts.clone());
+        let idx = futures.len();
+        futures.push(async move {
+            // Closed only if the semaphore is dropped, which outlives every search using it
+            let _slot = slots.acquire().await;
+            let start = std::time::Instant::now();
+            let run = async {
+                match host {
+                    Some(host) if !limiter.acquire(host, MAX_RATE_LIMIT_WAIT).await => Err(anyhow::anyhow!("rate limited ({})", host)),
+                    _ => search.await,
+                }
+            };
+            let res = match budget {
+                Some(budget) => tokio::time::timeout(budget, run).await
+                    .unwrap_or_else(|_| Err(anyhow::anyhow!("timed out after {}ms", budget.as_millis()))),
+                None => run.await,
+            };
+            let duration_ms = start.elapsed().as_millis() as u64;
+            let (res, error) = match res {
+                Ok(mut r) => {
+                    if let Some(cap) = cap { r.truncate(cap); }
+                    (r, None)
+                },
+                Err(e) => (vec![], Some(e.to_string())),
+            };
+            tracing::info!(results = res.len(), duration_ms, error = error.as_deref(), "provider finished");
+            (idx, res.clone(), ProviderStatus { name, count: res.len(), duration_ms, error })
+        }.instrument(span));
     }
 
-    let results_list = join_all(futures).await;
+    // Engines without a date filter can still return stale hits; drop the ones we can date,
+    // along with anything outside the requested domains
+    let cutoff = timeframe_secs(timeframe.as_deref()).map(|secs| chrono::Utc::now() - chrono::Duration::seconds(secs));
+    let keep = |r: &SearchResult| match cutoff {
+        Some(cutoff) => r.date.as_deref().and_then(parse_date).is_none_or(|d| d >= cutoff),
+        None => true,
+    } && opts.allows_url(&r.url);
+
+    let mut pending: stream::FuturesUnordered<_> = futures.into_iter().collect();
+    let mut results_list: Vec<(usize, Vec<SearchResult>, ProviderStatus)> = Vec::new();
+    let mut emitted = HashSet::new();
+    while let Some((idx, res, status)) = pending.next().await {
+        if let Some(tx) = &progress {
+            let fresh: Vec<SearchResult> = res.iter()
+                .filter(|r| keep(r) && emitted.insert(r.url.clone()))
+                .cloned()
+                .collect();
+            // The receiver going away (client disconnected) shouldn't stop the search
+            let _ = tx.send(ResultBatch { provider: status.name.clone(), results: fresh });
+        }
+        results_list.push((idx, res, status));
+    }
+    // Back to provider order so dedup keeps the same winner regardless of who finished first
+    results_list.sort_by_key(|(idx, _, _)| *idx);
     let mut all = Vec::new();
-    for res in results_list { all.extend(res); }
+    let mut statuses = Vec::new();
+    for (_, res, status) in results_list {
+        all.extend(res);
+        statuses.push(status);
+    }
 
     let mut seen = HashSet::new();
     let mut unique = Vec::new();
@@ -198,30 +478,52 @@ pub async fn perform_search(
         }
     }
     
-    // Sort relevance locally
+    unique.retain(keep);
+
+    // Sort relevance locally, with newer results boosted when a timeframe was asked for
     let q_low = query.to_lowercase();
-    unique.sort_by(|a, b| {
-        let ascore = if a.title.to_lowercase().contains(&q_low) { 1 } else { 0 };
-        let bscore = if b.title.to_lowercase().contains(&q_low) { 1 } else { 0 };
-        bscore.cmp(&ascore)
-    });
-    
-    unique
+    let window = timeframe_secs(timeframe.as_deref());
+    let weight = std::env::var("RECENCY_WEIGHT").ok().and_then(|v| v.parse::<f64>().ok()).unwrap_or(0.5);
+    let now = chrono::Utc::now();
+    let score = |r: &SearchResult| {
+        let relevance = if r.title.to_lowercase().contains(&q_low) { 1.0 } else { 0.0 };
+        let recency = match (window, r.date.as_deref().and_then(parse_date)) {
+            // Halves every window length, so the start of the window scores 0.5
+            (Some(w), Some(d)) => 0.5f64.powf((now - d).num_seconds().max(0) as f64 / w as f64),
+            _ => 0.0,
+        };
+        relevance + weight * recency
+    };
+    unique.sort_by(|a, b| score(b).total_cmp(&score(a)));
+
+    tracing::debug!(elapsed_ms = started.elapsed().as_millis() as u64, "search finished");
+    SearchOutcome { results: unique, providers: statuses }
+}
+
+/// Cuts `text` to at most `max` chars, backing up to the last word boundary and appending `…`.
+pub fn truncate_snippet(text: &str, max: usize) -> String {
+    if text.chars().count() <= max { return text.to_string(); }
+    let cut: String = text.chars().take(max).collect();
+    let cut = match cut.rfind(char::is_whitespace) {
+        Some(pos) if pos > 0 => &cut[..pos],
+        _ => cut.as_str(),
+    };
+    format!("{}…", cut.trim_end())
 }
 
 // --- Native Impls ---
 
-async fn local_db_search(query: String) -> Vec<SearchResult> {
-    let files: Vec<PathBuf> = match std::fs::read_dir(".") {
-        Ok(entries) => entries
+async fn local_db_search(query: String, scope: Option<Vec<PathBuf>>, role_filter: Option<String>, prefer_assistant: bool) -> anyhow::Result<Vec<SearchResult>> {
+    let files: Vec<PathBuf> = match scope {
+        Some(files) => files,
+        None => std::fs::read_dir(".")?
             .filter_map(|e| e.ok())
             .map(|e| e.path())
-            .filter(|p| p.extension().map_or(false, |ext| ext == "db"))
+            .filter(|p| p.extension().is_some_and(|ext| ext == "db"))
             .collect(),
-        Err(_) => return vec![]
     };
 
-    if files.is_empty() { return vec![]; }
+    if files.is_empty() { return Ok(vec![]); }
 
     let task = tokio::task::spawn_blocking(move || {
         let mut results = Vec::new();
@@ -250,7 +552,8 @@ async fn local_db_search(query: String) -> Vec<SearchResult> {
                             title: format!("[Local: {}] NOTE: {}", filename, row.get::<_,String>(1)?),
                             url: format!("local://{}/notes/{}", filename, row.get::<_,String>(1)?),
                             content: format!("(Summary updated: {}) {}", row.get::<_,String>(2)?, row.get::<_,String>(0)?),
-                            engine: "LocalDB".into()
+                            engine: "LocalDB".into(),
+                            ..Default::default()
                         })
                     });
                     if let Ok(iter) = notes_rows { for r in iter.flatten() { results.push(r); } }
@@ -264,22 +567,22 @@ async fn local_db_search(query: String) -> Vec<SearchResult> {
                 ).unwrap_or(false);
 
                 // Use simple struct to hold raw hits before fetching full context
-                struct RawHit { id: i64, conv_id: i64, date: String }
+                struct RawHit { id: i64, conv_id: i64, role: String }
 
                 let sql = if has_fts {
                     // Join FTS with Messages to get Created_At for sorting
-                    "SELECT m.id, m.conversation_id, m.created_at 
+                    "SELECT m.id, m.conversation_id, m.role 
                      FROM messages_fts f 
                      JOIN messages m ON f.rowid = m.id 
-                     WHERE messages_fts MATCH ? 
+                     WHERE messages_fts MATCH ?1 AND (?3 IS NULL OR m.role = ?3) 
                      ORDER BY m.created_at DESC 
-                     LIMIT ?"
+                     LIMIT ?2"
                 } else {
-                    "SELECT id, conversation_id, created_at 
+                    "SELECT id, conversation_id, role 
                      FROM messages 
-                     WHERE content LIKE '%' || ? || '%' 
+                     WHERE content LIKE '%' || ?1 || '%' AND (?3 IS NULL OR role = ?3) 
                      ORDER BY created_at DESC 
-                     LIMIT ?"
+                     LIMIT ?2"
                 };
 
                 // Remove quotes for broader FTS match
@@ -287,11 +590,11 @@ async fn local_db_search(query: String) -> Vec<SearchResult> {
 
                 let mut raw_hits = Vec::new();
                 if let Ok(mut stmt) = conn.prepare(sql) {
-                    let rows = stmt.query_map(params![param, limit_raw_hits], |row| {
+                    let rows = stmt.query_map(params![param, limit_raw_hits, role_filter], |row| {
                         Ok(RawHit { 
                             id: row.get(0)?, 
-                            conv_id: row.get(1)?, 
-                            date: row.get(2)? 
+                            conv_id: row.get(1)?,
+                            role: row.get(2)?
                         })
                     });
                     if let Ok(iter) = rows {
@@ -299,6 +602,12 @@ async fn local_db_search(query: String) -> Vec<SearchResult> {
                     }
                 }
 
+                // Assistant answers are distilled summaries, so let them win the per-conversation pick.
+                // Stable sort keeps newest-first order within each role.
+                if prefer_assistant {
+                    raw_hits.sort_by_key(|h| h.role != "assistant");
+                }
+
                 // Filter Logic: Ensure diversity by taking only 1 hit per conversation
                 let mut seen_convs = HashSet::new();
                 let mut diverse_hits = Vec::new();
@@ -336,7 +645,8 @@ async fn local_db_search(query: String) -> Vec<SearchResult> {
                                         title: format!("[Local: {}] Chat: {}", filename, chat_title),
                                         url: format!("local://{}/chat/{}/{}", filename, chat_title, hit.id), 
                                         content: full_transcript,
-                                        engine: "LocalDB".into()
+                                        engine: "LocalDB".into(),
+                                        ..Default::default()
                                     });
                                 }
                             }
@@ -348,168 +658,996 @@ async fn local_db_search(query: String) -> Vec<SearchResult> {
         results
     });
 
-    match task.await {
-        Ok(res) => res,
-        Err(_) => vec![]
-    }
+    Ok(task.await?)
 }
 
-async fn searxng_search(client: Client, query: String, timeframe: Option<String>) -> Vec<SearchResult> {
-    let base = std::env::var("SEARXNG_URL").unwrap_or_default();
-    if base.is_empty() { return vec![]; }
-    let mut url = format!("{}/search?q={}&format=json", base, urlencoding::encode(&query));
-    if let Some(tf) = timeframe {
-        if ["day", "week", "month"].contains(&tf.as_str()) { url.push_str(&format!("&time_range={}", tf)); }
-    }
-    if let Ok(resp) = client.get(&url).send().await {
-        if let Ok(json) = resp.json::<serde_json::Value>().await {
-             if let Some(arr) = json["results"].as_array() {
-                 return arr.iter().map(|r| SearchResult{
-                     title: r["title"].as_str().unwrap_or("").into(),
-                     url: r["url"].as_str().unwrap_or("").into(),
-                     content: r["content"].as_str().unwrap_or("").into(),
-                     engine: "SearXNG".into()
-                 }).collect();
-             }
+/// GET with the configured (possibly rotated) search User-Agent.
+fn get(client: &Client, url: &str) -> reqwest::RequestBuilder {
+    client.get(url).header(reqwest::header::USER_AGENT, crate::net::user_agent())
+}
+
+/// Sends `req` with retries and turns a non-2xx answer into an error, so the provider's
+/// status shows what went wrong instead of an empty result list.
+async fn fetch(req: reqwest::RequestBuilder) -> anyhow::Result<reqwest::Response> {
+    let resp = crate::net::send_with_retry(req).await?;
+    if !resp.status().is_success() { anyhow::bail!("HTTP {}", resp.status()); }
+    Ok(resp)
+}
+
+async fn fetch_json(req: reqwest::RequestBuilder) -> anyhow::Result<serde_json::Value> {
+    fetch(req).await?.json().await.map_err(|e| anyhow::anyhow!("response is not JSON: {}", e))
+}
+
+/// SearXNG instances to try in order: `SEARXNG_URLS` (comma-separated), else the single `SEARXNG_URL`.
+pub fn searxng_instances() -> Vec<String> {
+    let raw = std::env::var("SEARXNG_URLS").ok().filter(|v| !v.trim().is_empty())
+        .or_else(|| std::env::var("SEARXNG_URL").ok())
+        .unwrap_or_default();
+    raw.split(',').map(|u| u.trim().trim_end_matches('/').to_string()).filter(|u| !u.is_empty()).collect()
+}
+
+async fn searxng_search(client: Client, query: String, timeframe: Option<String>, lang: Option<String>, region: Option<String>) -> anyhow::Result<Vec<SearchResult>> {
+    let instances = searxng_instances();
+    if instances.is_empty() { anyhow::bail!("SEARXNG_URL is not set"); }
+    let mut last_error = None;
+    for base in instances {
+        let mut url = format!("{}/search?q={}&format=json", base, urlencoding::encode(&query));
+        if let Some(tf) = &timeframe {
+            if ["day", "week", "month"].contains(&tf.as_str()) { url.push_str(&format!("&time_range={}", tf)); }
+        }
+        // SearXNG takes `de` or `de-DE`
+        if let Some(lang) = &lang {
+            let language = match &region { Some(r) if !lang.contains('-') => format!("{}-{}", lang, r.to_uppercase()), _ => lang.clone() };
+            url.push_str(&format!("&language={}", urlencoding::encode(&language)));
         }
+        // An instance that answers with HTML (rate limit page, json format disabled) counts as a failure too
+        let json = match fetch_json(get(&client, &url)).await {
+            Ok(json) => json,
+            Err(e) => { tracing::warn!(instance = %base, "SearXNG instance failed: {}", e); last_error = Some(e); continue; },
+        };
+        if let Some(arr) = json["results"].as_array() {
+            tracing::debug!(instance = %base, "SearXNG query served");
+            return Ok(arr.iter().map(|r| SearchResult{
+                title: r["title"].as_str().unwrap_or("").into(),
+                url: r["url"].as_str().unwrap_or("").into(),
+                content: r["content"].as_str().unwrap_or("").into(),
+                engine: "SearXNG".into(),
+                date: r["publishedDate"].as_str().and_then(iso_date),
+            }).collect());
+        }
+        tracing::warn!(instance = %base, "SearXNG instance returned no results array");
+        last_error = Some(anyhow::anyhow!("unexpected response from {}", base));
     }
-    vec![]
+    Err(last_error.unwrap_or_else(|| anyhow::anyhow!("no SearXNG instance answered")))
 }
 
-async fn ddg_web(client: Client, q: String, timeframe: Option<String>) -> Vec<SearchResult> {
+async fn ddg_web(client: Client, q: String, timeframe: Option<String>, lang: Option<String>, region: Option<String>) -> anyhow::Result<Vec<SearchResult>> {
     let mut url = format!("https://duckduckgo.com/html/?q={}&kp=1", urlencoding::encode(&q));
     if let Some(tf) = timeframe {
         let df = match tf.as_str() { "day" => "d", "week" => "w", "month" => "m", _ => "" };
         if !df.is_empty() { url.push_str(&format!("&df={}", df)); }
     }
-    if let Ok(resp) = client.get(&url).send().await {
-        let html = resp.text().await.unwrap_or_default();
-        let doc = Html::parse_document(&html);
-        let res_sel = Selector::parse(".result").unwrap();
-        let a_sel = Selector::parse("a.result__a").unwrap();
-        let s_sel = Selector::parse(".result__snippet").unwrap();
-        let mut out = Vec::new();
-        for el in doc.select(&res_sel) {
-            if let Some(a) = el.select(&a_sel).next() {
-                out.push(SearchResult {
-                    title: a.text().collect::<String>().trim().into(),
-                    url: a.value().attr("href").unwrap_or("").into(),
-                    content: el.select(&s_sel).next().map(|s| s.text().collect::<String>()).unwrap_or_default().trim().into(),
-                    engine: "DuckDuckGo".into()
-                });
-            }
+    // DDG regions are country-language pairs like `de-de` or `ch-fr`
+    if let Some(region) = region {
+        let kl = if region.contains('-') { region } else { format!("{}-{}", region, lang.as_deref().unwrap_or("en")) };
+        url.push_str(&format!("&kl={}", kl.to_lowercase()));
+    }
+    let html = fetch(get(&client, &url)).await?.text().await?;
+    let doc = Html::parse_document(&html);
+    let res_sel = Selector::parse(".result").unwrap();
+    let a_sel = Selector::parse("a.result__a").unwrap();
+    let s_sel = Selector::parse(".result__snippet").unwrap();
+    let mut out = Vec::new();
+    for el in doc.select(&res_sel) {
+        if let Some(a) = el.select(&a_sel).next() {
+            out.push(SearchResult {
+                title: a.text().collect::<String>().trim().into(),
+                url: a.value().attr("href").unwrap_or("").into(),
+                content: el.select(&s_sel).next().map(|s| s.text().collect::<String>()).unwrap_or_default().trim().into(),
+                engine: "DuckDuckGo".into(),
+                ..Default::default()
+            });
         }
-        out
-    } else { vec![] }
+    }
+    Ok(out)
 }
 
-async fn qwant_web(client: Client, q: String) -> Vec<SearchResult> {
+async fn qwant_web(client: Client, q: String) -> anyhow::Result<Vec<SearchResult>> {
     let url = format!("https://www.qwant.com/?q={}&t=web", urlencoding::encode(&q));
-    match client.get(&url).send().await {
-        Ok(resp) => {
-            let html = resp.text().await.unwrap_or_default();
-            let fragment = Html::parse_document(&html);
-            let result_sel = Selector::parse("[data-testid=\"result-card\"]").unwrap();
-            let mut out = Vec::new();
-            for el in fragment.select(&result_sel) {
-                 let link_sel = Selector::parse("a").unwrap();
-                 if let Some(a) = el.select(&link_sel).next() {
-                     let title = a.text().collect::<String>().trim().to_string();
-                     let url = a.value().attr("href").unwrap_or("").to_string();
-                     if !url.is_empty() {
-                         out.push(SearchResult { title, url, content: "Qwant Result".into(), engine: "Qwant".into() });
-                     }
-                 }
+    let html = fetch(get(&client, &url)).await?.text().await?;
+    let fragment = Html::parse_document(&html);
+    let result_sel = Selector::parse("[data-testid=\"result-card\"]").unwrap();
+    let link_sel = Selector::parse("a").unwrap();
+    let mut out = Vec::new();
+    for el in fragment.select(&result_sel) {
+        if let Some(a) = el.select(&link_sel).next() {
+            let title = a.text().collect::<String>().trim().to_string();
+            let url = a.value().attr("href").unwrap_or("").to_string();
+            if !url.is_empty() {
+                out.push(SearchResult { title, url, content: "Qwant Result".into(), engine: "Qwant".into(), ..Default::default() });
             }
-            out
-        },
-        Err(_) => vec![]
+        }
     }
+    Ok(out)
 }
 
-async fn mojeek_web(client: Client, q: String) -> Vec<SearchResult> {
+async fn mojeek_web(client: Client, q: String) -> anyhow::Result<Vec<SearchResult>> {
     let url = format!("https://www.mojeek.com/search?q={}", urlencoding::encode(&q));
-    if let Ok(resp) = client.get(&url).send().await {
-        let html = resp.text().await.unwrap_or_default();
-        let doc = Html::parse_document(&html);
-        let sel = Selector::parse("div.results div.result").unwrap();
-        let mut out = Vec::new();
-        for el in doc.select(&sel) {
-            if let Some(a) = el.select(&Selector::parse("a").unwrap()).next() {
-                out.push(SearchResult {
-                    title: a.text().collect::<String>().trim().into(),
-                    url: a.value().attr("href").unwrap_or("").into(),
-                    content: el.select(&Selector::parse("p.s").unwrap()).next().map(|s| s.text().collect::<String>()).unwrap_or_default(),
-                    engine: "Mojeek".into()
-                });
-            }
+    let html = fetch(get(&client, &url)).await?.text().await?;
+    let doc = Html::parse_document(&html);
+    let sel = Selector::parse("div.results div.result").unwrap();
+    let mut out = Vec::new();
+    for el in doc.select(&sel) {
+        if let Some(a) = el.select(&Selector::parse("a").unwrap()).next() {
+            out.push(SearchResult {
+                title: a.text().collect::<String>().trim().into(),
+                url: a.value().attr("href").unwrap_or("").into(),
+                content: el.select(&Selector::parse("p.s").unwrap()).next().map(|s| s.text().collect::<String>()).unwrap_or_default(),
+                engine: "Mojeek".into(),
+                ..Default::default()
+            });
         }
-        out
-    } else { vec![] }
-}
-
-async fn wikipedia_web(client: Client, q: String) -> Vec<SearchResult> {
-    let url = format!("https://en.wikipedia.org/w/api.php?action=query&list=search&utf8=1&format=json&srsearch={}", urlencoding::encode(&q));
-    if let Ok(resp) = client.get(&url).send().await {
-        if let Ok(json) = resp.json::<serde_json::Value>().await {
-            if let Some(arr) = json["query"]["search"].as_array() {
-                return arr.iter().map(|i| SearchResult{
-                    title: i["title"].as_str().unwrap_or("").into(),
-                    url: format!("https://en.wikipedia.org/wiki/{}", i["title"].as_str().unwrap_or("").replace(" ","_")),
-                    content: i["snippet"].as_str().unwrap_or("").replace("<span class=\"searchmatch\">","").replace("</span>",""),
-                    engine: "Wikipedia".into()
-                }).collect();
-            }
+    }
+    Ok(out)
+}
+
+async fn ecosia_web(client: Client, q: String) -> anyhow::Result<Vec<SearchResult>> {
+    let url = format!("https://www.ecosia.org/search?q={}", urlencoding::encode(&q));
+    let html = fetch(get(&client, &url)).await?.text().await?;
+    let doc = Html::parse_document(&html);
+    let sel = Selector::parse(".result").unwrap();
+    let a_sel = Selector::parse("a.result-title, a.result__link, h2 a, a").unwrap();
+    let s_sel = Selector::parse(".result-snippet, .result__description, p").unwrap();
+    let mut out = Vec::new();
+    for el in doc.select(&sel) {
+        if let Some(a) = el.select(&a_sel).next() {
+            let url = a.value().attr("href").unwrap_or("");
+            if !url.starts_with("http") { continue; }
+            out.push(SearchResult {
+                title: a.text().collect::<String>().trim().into(),
+                url: url.into(),
+                content: el.select(&s_sel).next().map(|s| s.text().collect::<String>()).unwrap_or_default().trim().into(),
+                engine: "Ecosia".into(),
+                ..Default::default()
+            });
         }
     }
-    vec![]
+    Ok(out)
 }
 
-async fn reddit_web(client: Client, q: String) -> Vec<SearchResult> {
-    let url = format!("https://www.reddit.com/search.json?q={}&sort=relevance&limit=10", urlencoding::encode(&q));
-    if let Ok(resp) = client.get(&url).send().await {
-        if let Ok(json) = resp.json::<serde_json::Value>().await {
-            if let Some(arr) = json["data"]["children"].as_array() {
-                return arr.iter().map(|c| SearchResult{
-                    title: c["data"]["title"].as_str().unwrap_or("").into(),
-                    url: format!("https://www.reddit.com{}", c["data"]["permalink"].as_str().unwrap_or("")),
-                    content: c["data"]["selftext"].as_str().unwrap_or("").chars().take(200).collect(),
-                    engine: "Reddit".into()
-                }).collect();
-            }
+async fn wikipedia_web(client: Client, q: String, lang: Option<String>) -> anyhow::Result<Vec<SearchResult>> {
+    let host = wikipedia_host(lang.as_deref());
+    let url = format!("https://{}/w/api.php?action=query&list=search&utf8=1&format=json&srsearch={}", host, urlencoding::encode(&q));
+    let json = fetch_json(get(&client, &url)).await?;
+    Ok(json["query"]["search"].as_array().into_iter().flatten().map(|i| SearchResult{
+        title: i["title"].as_str().unwrap_or("").into(),
+        url: format!("https://{}/wiki/{}", host, i["title"].as_str().unwrap_or("").replace(" ","_")),
+        content: i["snippet"].as_str().unwrap_or("").replace("<span class=\"searchmatch\">","").replace("</span>",""),
+        engine: "Wikipedia".into(),
+        ..Default::default()
+    }).collect())
+}
+
+/// Language edition to search: `de` -> `de.wikipedia.org`; region suffixes (`pt-BR`) are dropped.
+fn wikipedia_host(lang: Option<&str>) -> String {
+    let code = lang.and_then(|l| l.split('-').next()).filter(|l| !l.is_empty()).unwrap_or("en");
+    format!("{}.wikipedia.org", code.to_lowercase())
+}
+
+/// Best-effort parse of the dates providers hand back: unix seconds (or millis) past 2001,
+/// a bare 4-digit year (Jan 1), RFC 3339 / RFC 2822, ISO 8601 without an offset (taken as UTC)
+/// or a bare `YYYY-MM-DD`. Other numbers aren't dates.
+pub fn parse_date(raw: &str) -> Option<chrono::DateTime<chrono::Utc>> {
+    use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
+    let raw = raw.trim().trim_matches('"');
+    if raw.is_empty() { return None; }
+    if raw.len() == 4 && raw.bytes().all(|b| b.is_ascii_digit()) {
+        return NaiveDate::from_ymd_opt(raw.parse().ok()?, 1, 1).and_then(|d| d.and_hms_opt(0, 0, 0)).map(|d| d.and_utc());
+    }
+    if let Ok(n) = raw.parse::<f64>() {
+        let secs = if n > 1e12 { n / 1000.0 } else if n > 1e9 { n } else { return None };
+        return DateTime::from_timestamp(secs as i64, 0);
+    }
+    DateTime::parse_from_rfc3339(raw).or_else(|_| DateTime::parse_from_rfc2822(raw)).map(|d| d.with_timezone(&Utc)).ok()
+        .or_else(|| NaiveDateTime::parse_from_str(raw, "%Y-%m-%d %H:%M:%S").ok().map(|d| d.and_utc()))
+        .or_else(|| NaiveDateTime::parse_from_str(raw, "%Y-%m-%dT%H:%M:%S%.f").ok().map(|d| d.and_utc()))
+        .or_else(|| NaiveDate::parse_from_str(raw, "%Y-%m-%d").ok().and_then(|d| d.and_hms_opt(0, 0, 0)).map(|d| d.and_utc()))
+}
+
+fn iso_date(raw: &str) -> Option<String> {
+    parse_date(raw).map(|d| d.to_rfc3339_opts(chrono::SecondsFormat::Secs, true))
+}
+
+/// Length of a UI timeframe (`day`, `week`, `month`) in seconds.
+fn timeframe_secs(timeframe: Option<&str>) -> Option<i64> {
+    match timeframe? {
+        "day" => Some(86_400),
+        "week" => Some(7 * 86_400),
+        "month" => Some(31 * 86_400),
+        _ => None,
+    }
+}
+
+async fn reddit_web(client: Client, q: String, timeframe: Option<String>) -> anyhow::Result<Vec<SearchResult>> {
+    let mut url = format!("https://www.reddit.com/search.json?q={}&sort=relevance&limit=10", urlencoding::encode(&q));
+    if timeframe_secs(timeframe.as_deref()).is_some() {
+        url.push_str(&format!("&t={}", timeframe.unwrap_or_default()));
+    }
+    let json = fetch_json(get(&client, &url)).await?;
+    Ok(json["data"]["children"].as_array().into_iter().flatten().map(|c| SearchResult{
+        title: c["data"]["title"].as_str().unwrap_or("").into(),
+        url: format!("https://www.reddit.com{}", c["data"]["permalink"].as_str().unwrap_or("")),
+        content: c["data"]["selftext"].as_str().unwrap_or("").chars().take(200).collect(),
+        engine: "Reddit".into(),
+        date: iso_date(&c["data"]["created_utc"].to_string()),
+    }).collect())
+}
+
+async fn stackexchange_web(client: Client, q: String, timeframe: Option<String>) -> anyhow::Result<Vec<SearchResult>> {
+    let mut url = format!("https://api.stackexchange.com/2.3/search/advanced?order=desc&sort=relevance&q={}&site=stackoverflow&filter=withbody", urlencoding::encode(&q));
+    if let Some(secs) = timeframe_secs(timeframe.as_deref()) {
+        url.push_str(&format!("&fromdate={}", chrono::Utc::now().timestamp() - secs));
+    }
+    let json = fetch_json(get(&client, &url)).await?;
+    Ok(stackexchange_results(&json))
+}
+
+fn stackexchange_results(json: &serde_json::Value) -> Vec<SearchResult> {
+    json["items"].as_array().into_iter().flatten().map(|i| {
+        let tags = i["tags"].as_array()
+            .map(|t| t.iter().filter_map(|v| v.as_str()).collect::<Vec<_>>().join(", "))
+            .unwrap_or_default();
+        // Bodies come back as HTML, keep only the text
+        let body = Html::parse_fragment(i["body"].as_str().unwrap_or(""))
+            .root_element().text().collect::<String>();
+        let body = body.split_whitespace().collect::<Vec<_>>().join(" ");
+        let mut excerpt: String = body.chars().take(300).collect();
+        if body.chars().count() > 300 { excerpt.push('…'); }
+        SearchResult{
+            title: i["title"].as_str().unwrap_or("").into(),
+            url: i["link"].as_str().unwrap_or("").into(),
+            content: format!("Score: {} | Answers: {} | Tags: {}\n{}",
+                i["score"].as_i64().unwrap_or(0), i["answer_count"].as_u64().unwrap_or(0), tags, excerpt),
+            engine: "StackOverflow".into(),
+            date: iso_date(&i["creation_date"].to_string()),
         }
+    }).collect()
+}
+
+async fn github_web(client: Client, q: String) -> anyhow::Result<Vec<SearchResult>> {
+    let url = format!("https://api.github.com/search/repositories?q={}&per_page=10", urlencoding::encode(&q));
+    let mut req = get(&client, &url).header("Accept", "application/vnd.github+json");
+    // Unauthenticated search is limited to a handful of requests per minute
+    if let Ok(token) = std::env::var("GITHUB_TOKEN") {
+        if !token.is_empty() { req = req.bearer_auth(token); }
+    }
+    let resp = crate::net::send_with_retry(req).await?;
+    let rate_limited = resp.headers().get("x-ratelimit-remaining").is_some_and(|v| v == "0");
+    if resp.status() == reqwest::StatusCode::FORBIDDEN && rate_limited {
+        tracing::warn!("GitHub search rate limit reached, set GITHUB_TOKEN to raise it");
+        anyhow::bail!("rate limit reached, set GITHUB_TOKEN to raise it");
     }
-    vec![]
+    if !resp.status().is_success() { anyhow::bail!("HTTP {}", resp.status()); }
+    let json = resp.json::<serde_json::Value>().await
+        .map_err(|e| anyhow::anyhow!("response is not JSON: {}", e))?;
+    Ok(json["items"].as_array().into_iter().flatten().map(|i| SearchResult{
+        title: i["full_name"].as_str().unwrap_or("").into(),
+        url: i["html_url"].as_str().unwrap_or("").into(),
+        content: format!("{} (★ {})", i["description"].as_str().unwrap_or(""), i["stargazers_count"].as_u64().unwrap_or(0)),
+        engine: "GitHub".into(),
+        ..Default::default()
+    }).collect())
 }
 
-async fn stackexchange_web(client: Client, q: String) -> Vec<SearchResult> {
-    let url = format!("https://api.stackexchange.com/2.3/search/advanced?order=desc&sort=relevance&q={}&site=stackoverflow", urlencoding::encode(&q));
-    if let Ok(resp) = client.get(&url).send().await {
-        if let Ok(json) = resp.json::<serde_json::Value>().await {
-            if let Some(arr) = json["items"].as_array() {
-                return arr.iter().map(|i| SearchResult{
-                    title: i["title"].as_str().unwrap_or("").into(),
-                    url: i["link"].as_str().unwrap_or("").into(),
-                    content: format!("Score: {}", i["score"]),
-                    engine: "StackOverflow".into()
-                }).collect();
-            }
+async fn youtube_search(client: Client, q: String) -> anyhow::Result<Vec<SearchResult>> {
+    let key = std::env::var("YOUTUBE_API_KEY").ok().filter(|k| !k.is_empty())
+        .ok_or_else(|| anyhow::anyhow!("YOUTUBE_API_KEY is not set"))?;
+    let url = format!(
+        "https://www.googleapis.com/youtube/v3/search?part=snippet&type=video&maxResults=10&q={}&key={}",
+        urlencoding::encode(&q), urlencoding::encode(&key)
+    );
+    let json = fetch_json(get(&client, &url)).await?;
+    Ok(json["items"].as_array().into_iter().flatten().filter_map(|i| {
+        let id = i["id"]["videoId"].as_str()?;
+        Some(SearchResult{
+            title: i["snippet"]["title"].as_str().unwrap_or("").into(),
+            url: format!("https://youtube.com/watch?v={}", id),
+            content: i["snippet"]["description"].as_str().unwrap_or("").into(),
+            engine: "YouTube".into(),
+            date: i["snippet"]["publishedAt"].as_str().and_then(iso_date),
+        })
+    }).collect())
+}
+
+async fn marginalia_search(client: Client, q: String) -> anyhow::Result<Vec<SearchResult>> {
+    let key = std::env::var("MARGINALIA_API_KEY").ok().filter(|k| !k.is_empty())
+        .ok_or_else(|| anyhow::anyhow!("MARGINALIA_API_KEY is not set"))?;
+    let url = format!(
+        "https://api.marginalia-search.com/{}/search/{}?count=10",
+        urlencoding::encode(&key), urlencoding::encode(&q)
+    );
+    let json = fetch_json(get(&client, &url)).await?;
+    Ok(json["results"].as_array().into_iter().flatten().filter_map(|i| {
+        Some(SearchResult{
+            title: i["title"].as_str().unwrap_or("").into(),
+            url: i["url"].as_str()?.into(),
+            content: i["description"].as_str().unwrap_or("").into(),
+            engine: "Marginalia".into(),
+            ..Default::default()
+        })
+    }).collect())
+}
+
+async fn archive_org_search(client: Client, q: String) -> anyhow::Result<Vec<SearchResult>> {
+    let url = format!(
+        "https://archive.org/advancedsearch.php?q={}&fl[]=identifier&fl[]=title&fl[]=description&rows=10&output=json",
+        urlencoding::encode(&q)
+    );
+    let json = fetch_json(get(&client, &url)).await?;
+    Ok(json["response"]["docs"].as_array().into_iter().flatten().filter_map(|d| {
+        let id = d["identifier"].as_str()?;
+        // Metadata fields are either a string or a list of strings
+        let text = |v: &serde_json::Value| match v {
+            serde_json::Value::Array(a) => a.iter().filter_map(|x| x.as_str()).collect::<Vec<_>>().join(" "),
+            v => v.as_str().unwrap_or("").to_string(),
+        };
+        let description = Html::parse_fragment(&text(&d["description"])).root_element().text().collect::<String>();
+        Some(SearchResult{
+            title: text(&d["title"]),
+            url: format!("https://archive.org/details/{}", id),
+            content: description.chars().take(300).collect(),
+            engine: "Internet Archive".into(),
+            ..Default::default()
+        })
+    }).collect())
+}
+
+async fn lemmy_search(client: Client, q: String) -> anyhow::Result<Vec<SearchResult>> {
+    let instance = std::env::var("LEMMY_INSTANCE").ok().filter(|i| !i.trim().is_empty()).unwrap_or_else(|| "lemmy.world".into());
+    let base = if instance.starts_with("http") { instance.trim_end_matches('/').to_string() } else { format!("https://{}", instance.trim_end_matches('/')) };
+    let url = format!("{}/api/v3/search?q={}&type_=Posts&sort=TopAll&limit=10", base, urlencoding::encode(&q));
+    let json = fetch_json(get(&client, &url)).await?;
+    Ok(json["posts"].as_array().into_iter().flatten().map(|p| {
+        let post = &p["post"];
+        // Link posts point elsewhere, text posts only have their Lemmy permalink
+        let url = post["url"].as_str().filter(|u| !u.is_empty())
+            .or(post["ap_id"].as_str())
+            .map(String::from)
+            .unwrap_or_else(|| format!("{}/post/{}", base, post["id"]));
+        SearchResult{
+            title: post["name"].as_str().unwrap_or("").into(),
+            url,
+            content: post["body"].as_str().unwrap_or("").chars().take(200).collect(),
+            engine: "Lemmy".into(),
+            date: post["published"].as_str().and_then(iso_date),
         }
+    }).collect())
+}
+
+async fn semantic_scholar_search(client: Client, q: String) -> anyhow::Result<Vec<SearchResult>> {
+    let url = format!(
+        "https://api.semanticscholar.org/graph/v1/paper/search?query={}&fields=title,url,abstract,year,publicationDate&limit=10",
+        urlencoding::encode(&q)
+    );
+    let mut req = get(&client, &url);
+    // Works keyless, a key just raises the shared rate limit
+    if let Ok(key) = std::env::var("SEMANTIC_SCHOLAR_API_KEY") {
+        if !key.is_empty() { req = req.header("x-api-key", key); }
     }
-    vec![]
+    let json = fetch_json(req).await?;
+    // `data` is left out entirely when nothing matched
+    Ok(json["data"].as_array().into_iter().flatten().map(|p| {
+        let year = p["year"].as_u64().map(|y| format!("({}) ", y)).unwrap_or_default();
+        let abstract_: String = p["abstract"].as_str().unwrap_or("").chars().take(400).collect();
+        SearchResult{
+            title: p["title"].as_str().unwrap_or("").into(),
+            url: p["url"].as_str().unwrap_or("").into(),
+            content: format!("{}{}", year, abstract_).trim().to_string(),
+            engine: "Semantic Scholar".into(),
+            date: p["publicationDate"].as_str().and_then(iso_date),
+        }
+    }).collect())
 }
 
-pub async fn suggest(Query(p): Query<std::collections::HashMap<String,String>>) -> Json<Vec<String>> {
+async fn google_news_search(client: Client, q: String, timeframe: Option<String>, lang: Option<String>, region: Option<String>) -> anyhow::Result<Vec<SearchResult>> {
+    let when = match timeframe.as_deref() { Some("day") => " when:1d", Some("week") => " when:7d", Some("month") => " when:30d", _ => "" };
+    // Edition is language + country, e.g. hl=de&gl=DE&ceid=DE:de
+    let lang = lang.as_deref().and_then(|l| l.split('-').next()).unwrap_or("en").to_lowercase();
+    let country = region.unwrap_or_else(|| if lang == "en" { "US".into() } else { lang.clone() }).to_uppercase();
+    let url = format!(
+        "https://news.google.com/rss/search?q={}&hl={}&gl={}&ceid={}:{}",
+        urlencoding::encode(&format!("{}{}", q, when)), lang, country, country, lang
+    );
+    let xml = fetch(get(&client, &url)).await?.text().await?;
+    Ok(rss_items(&xml).into_iter().take(10).filter_map(|item| {
+        // The description is escaped HTML (a link plus the outlet name), keep just its text
+        let description = xml_text(item, "description").unwrap_or_default();
+        let content: String = Html::parse_fragment(&description).root_element().text().collect::<Vec<_>>().join(" ");
+        Some(SearchResult{
+            title: xml_text(item, "title")?,
+            url: xml_text(item, "link")?,
+            content: content.split_whitespace().collect::<Vec<_>>().join(" "),
+            engine: "Google News".into(),
+            date: xml_text(item, "pubDate").as_deref().and_then(iso_date),
+        })
+    }).collect())
+}
+
+/// The raw `<item>...</item>` bodies of an RSS feed.
+fn rss_items(xml: &str) -> Vec<&str> {
+    let mut items = Vec::new();
+    let mut rest = xml;
+    while let Some(start) = rest.find("<item>").or_else(|| rest.find("<item ")) {
+        let body = &rest[start..];
+        let Some(end) = body.find("</item>") else { break };
+        items.push(&body[..end]);
+        rest = &body[end..];
+    }
+    items
+}
+
+/// Text of the first `<tag>` in an RSS fragment, with CDATA unwrapped and entities decoded.
+/// (`scraper` can't be used here: HTML treats `<link>` as a void element and drops its text.)
+fn xml_text(fragment: &str, tag: &str) -> Option<String> {
+    let open = fragment.find(&format!("<{}", tag))?;
+    let after_open = &fragment[open..];
+    let body_start = after_open.find('>')? + 1;
+    let body_end = after_open.find(&format!("</{}>", tag))?;
+    let raw = after_open.get(body_start..body_end)?.trim();
+    let raw = raw.strip_prefix("<![CDATA[").and_then(|r| r.strip_suffix("]]>")).unwrap_or(raw);
+    let text = raw.replace("&lt;", "<").replace("&gt;", ">").replace("&quot;", "\"")
+        .replace("&#39;", "'").replace("&apos;", "'").replace("&nbsp;", " ").replace("&amp;", "&");
+    if text.is_empty() { None } else { Some(text) }
+}
+
+/// E-utilities takes two hops: esearch for matching PMIDs, then esummary for their metadata.
+async fn pubmed_search(client: Client, q: String) -> anyhow::Result<Vec<SearchResult>> {
+    const EUTILS: &str = "https://eutils.ncbi.nlm.nih.gov/entrez/eutils";
+    // Optional, raises the limit from 3 to 10 requests per second
+    let key = std::env::var("NCBI_API_KEY").ok().filter(|k| !k.is_empty())
+        .map(|k| format!("&api_key={}", urlencoding::encode(&k)))
+        .unwrap_or_default();
+
+    let url = format!("{}/esearch.fcgi?db=pubmed&term={}&retmode=json&retmax=10{}", EUTILS, urlencoding::encode(&q), key);
+    let json = fetch_json(get(&client, &url)).await?;
+    let ids: Vec<String> = json["esearchresult"]["idlist"].as_array().into_iter().flatten()
+        .filter_map(|id| id.as_str().map(String::from))
+        .collect();
+    if ids.is_empty() { return Ok(vec![]); }
+
+    let url = format!("{}/esummary.fcgi?db=pubmed&id={}&retmode=json{}", EUTILS, ids.join(","), key);
+    let json = fetch_json(get(&client, &url)).await?;
+    Ok(ids.iter().filter_map(|id| {
+        let doc = &json["result"][id.as_str()];
+        let title = doc["title"].as_str()?;
+        let authors: Vec<&str> = doc["authors"].as_array().into_iter().flatten()
+            .filter_map(|a| a["name"].as_str())
+            .take(3)
+            .collect();
+        let content = format!("{} {}. {}", doc["source"].as_str().unwrap_or(""), doc["pubdate"].as_str().unwrap_or(""), authors.join(", "));
+        Some(SearchResult{
+            title: title.into(),
+            url: format!("https://pubmed.ncbi.nlm.nih.gov/{}/", id),
+            content: content.trim().to_string(),
+            engine: "PubMed".into(),
+            // "2024/03/05 00:00"
+            date: doc["sortpubdate"].as_str().and_then(|d| iso_date(&d.chars().take(10).collect::<String>().replace('/', "-"))),
+        })
+    }).collect())
+}
+
+/// Asks the LLM for up to two rephrasings of `query` to search alongside it. Any failure
+/// (no model, timeout, unusable reply) just means no extra queries.
+pub async fn expand_query(provider: &str, model: &str, query: &str) -> Vec<String> {
+    let system = "You rewrite web search queries. Reply with the queries only, one per line.";
+    let prompt = format!(
+        "Write 2 alternative search engine queries for: \"{}\"\nUse synonyms or different wording but keep the meaning.",
+        query
+    );
+    let reply = match tokio::time::timeout(
+        std::time::Duration::from_secs(20),
+        crate::llm::complete(provider, model, system, &prompt),
+    ).await {
+        Ok(Ok(reply)) => reply,
+        Ok(Err(e)) => { tracing::warn!("Query expansion failed: {}", e); return vec![]; },
+        Err(_) => { tracing::warn!("Query expansion timed out"); return vec![]; },
+    };
+    let mut seen = HashSet::from([query.trim().to_lowercase()]);
+    reply.lines()
+        .map(|l| l.trim().trim_start_matches(|c: char| c.is_ascii_digit() || "-*•.) ".contains(c)))
+        .map(|l| l.strip_prefix('"').and_then(|l| l.strip_suffix('"')).unwrap_or(l).trim())
+        .filter(|l| !l.is_empty() && l.len() <= 200 && seen.insert(l.to_lowercase()))
+        .take(2)
+        .map(String::from)
+        .collect()
+}
+
+/// Whether `EMBEDDINGS_API_BASE` and `EMBEDDINGS_MODEL` are set, i.e. `rerank` can run.
+pub fn rerank_configured() -> bool {
+    ["EMBEDDINGS_API_BASE", "EMBEDDINGS_MODEL"].iter().all(|v| std::env::var(v).is_ok_and(|v| !v.trim().is_empty()))
+}
+
+/// Reorders `results` by cosine similarity between the query and each title + snippet,
+/// using an OpenAI-compatible `/embeddings` endpoint. Leaves the order alone on any error.
+pub async fn rerank(client: &Client, query: &str, results: &mut [SearchResult]) -> anyhow::Result<()> {
+    if results.len() < 2 { return Ok(()); }
+    let base = std::env::var("EMBEDDINGS_API_BASE")?;
+    let model = std::env::var("EMBEDDINGS_MODEL")?;
+    let mut input = vec![query.to_string()];
+    input.extend(results.iter().map(|r| format!("{}\n{}", r.title, truncate_snippet(&r.content, 1000))));
+
+    let mut req = client.post(format!("{}/embeddings", base.trim_end_matches('/')))
+        .json(&serde_json::json!({ "model": model, "input": input }));
+    if let Ok(key) = std::env::var("EMBEDDINGS_API_KEY") {
+        if !key.is_empty() { req = req.bearer_auth(key); }
+    }
+    let resp = req.send().await?.error_for_status()?;
+    let json: serde_json::Value = resp.json().await?;
+    let mut vectors: Vec<Vec<f64>> = vec![Vec::new(); input.len()];
+    for item in json["data"].as_array().ok_or_else(|| anyhow::anyhow!("embeddings response has no data"))? {
+        let idx = item["index"].as_u64().unwrap_or(0) as usize;
+        if let (Some(slot), Some(embedding)) = (vectors.get_mut(idx), item["embedding"].as_array()) {
+            *slot = embedding.iter().filter_map(|v| v.as_f64()).collect();
+        }
+    }
+    if vectors.iter().any(|v| v.is_empty()) {
+        anyhow::bail!("embeddings response is missing vectors");
+    }
+
+    let cosine = |a: &[f64], b: &[f64]| {
+        let dot: f64 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+        let norm = |v: &[f64]| v.iter().map(|x| x * x).sum::<f64>().sqrt();
+        dot / (norm(a) * norm(b)).max(f64::EPSILON)
+    };
+    let mut scored: Vec<(f64, SearchResult)> = results.iter().cloned().enumerate()
+        .map(|(i, r)| (cosine(&vectors[0], &vectors[i + 1]), r))
+        .collect();
+    scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+    for (slot, (_, r)) in results.iter_mut().zip(scored) { *slot = r; }
+    Ok(())
+}
+
+/// Characters of fetched page text kept per result (`DEEP_READ_MAX_CHARS`, default 3000).
+pub fn deep_read_max_chars() -> usize {
+    std::env::var("DEEP_READ_MAX_CHARS").ok().and_then(|v| v.parse().ok()).unwrap_or(3000)
+}
+
+/// Replaces the snippet of the top `DEEP_READ_TOP_K` results (default 3, max 10) with the
+/// main text of their pages. Pages that time out, aren't HTML, live on a non-public host or
+/// yield too little text keep their snippet. At most `DEEP_READ_MAX_BYTES` (default 2 MB) of each page is read.
+pub async fn deep_read(results: &mut [SearchResult]) {
+    let k = std::env::var("DEEP_READ_TOP_K").ok().and_then(|v| v.parse::<usize>().ok()).unwrap_or(3).min(10);
+    let timeout = std::time::Duration::from_secs(std::env::var("DEEP_READ_TIMEOUT_SECS").ok().and_then(|v| v.parse().ok()).unwrap_or(8));
+    let max_bytes = std::env::var("DEEP_READ_MAX_BYTES").ok().and_then(|v| v.parse().ok()).unwrap_or(2_000_000);
+    let max_chars = deep_read_max_chars();
+    let client = match page_client() {
+        Ok(c) => c,
+        Err(e) => {
+            tracing::error!("failed to build deep read client: {}", e);
+            return;
+        }
+    };
+
+    let fetches = results.iter().take(k).map(|r| {
+        let (client, url) = (client.clone(), r.url.clone());
+        async move {
+            let html = tokio::time::timeout(timeout, fetch_page(&client, &url, max_bytes)).await.ok().flatten()?;
+            let text = extract_main_text(&html);
+            // Cookie walls and JS-only pages leave next to nothing, the snippet is better then
+            if text.chars().count() < 200 { return None; }
+            Some(text.chars().take(max_chars).collect::<String>())
+        }
+    });
+    let texts = futures::future::join_all(fetches).await;
+    for (r, text) in results.iter_mut().zip(texts) {
+        if let Some(t) = text {
+            tracing::debug!(url = %r.url, chars = t.len(), "deep read");
+            r.content = t;
+        }
+    }
+}
+
+/// Client for `fetch_page`. It doesn't follow redirects itself, `fetch_page` does so
+/// by hand so every hop's host gets checked before it is fetched.
+pub fn page_client() -> reqwest::Result<Client> {
+    crate::net::client_builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .timeout(std::time::Duration::from_secs(15))
+        .build()
+}
+
+const DEEP_READ_MAX_HOPS: usize = 5;
+
+/// Fetches an HTML page from an arbitrary (search result) URL with a `page_client`, following
+/// up to `DEEP_READ_MAX_HOPS` redirects and refusing any hop to a loopback/private/link-local
+/// host or from https to http. At most `max_bytes` of the body are read.
+pub async fn fetch_page(client: &Client, url: &str, max_bytes: usize) -> Option<String> {
+    let mut url = reqwest::Url::parse(url).ok()?;
+    for _ in 0..=DEEP_READ_MAX_HOPS {
+        if !crate::net::is_public_url(&url).await {
+            tracing::debug!(%url, "deep read skipped non-public host");
+            return None;
+        }
+        let resp = get(client, url.as_str()).send().await.ok()?;
+        if resp.status().is_redirection() {
+            let location = resp.headers().get(reqwest::header::LOCATION)?.to_str().ok()?;
+            let next = url.join(location).ok()?;
+            if url.scheme() == "https" && next.scheme() == "http" { return None; }
+            url = next;
+            continue;
+        }
+        let is_html = resp.headers().get(reqwest::header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|ct| ct.contains("text/html") || ct.contains("application/xhtml"));
+        if !resp.status().is_success() || !is_html { return None; }
+        return read_capped(resp, max_bytes).await;
+    }
+    None
+}
+
+/// Reads at most `max_bytes` of the body, dropping the rest unread.
+async fn read_capped(mut resp: reqwest::Response, max_bytes: usize) -> Option<String> {
+    let mut body = Vec::new();
+    while let Some(chunk) = resp.chunk().await.ok()? {
+        let room = max_bytes - body.len();
+        body.extend_from_slice(&chunk[..chunk.len().min(room)]);
+        if body.len() >= max_bytes { break; }
+    }
+    Some(String::from_utf8_lossy(&body).into_owned())
+}
+
+/// Readability-style guess at a page's article text: the paragraphs of `<article>`/`<main>`
+/// if present, else of whichever element directly holds the most paragraph text.
+fn extract_main_text(html: &str) -> String {
+    let doc = Html::parse_document(html);
+    let p_sel = Selector::parse("p").unwrap();
+    let main_sel = Selector::parse("article, main, [role=main]").unwrap();
+    let para_text = |p: scraper::ElementRef| p.text().collect::<String>().split_whitespace().collect::<Vec<_>>().join(" ");
+
+    let paragraphs: Vec<String> = if let Some(main) = doc.select(&main_sel).max_by_key(|m| m.select(&p_sel).map(|p| para_text(p).len()).sum::<usize>()) {
+        main.select(&p_sel).map(para_text).collect()
+    } else {
+        let mut by_parent: std::collections::HashMap<_, (usize, Vec<String>)> = std::collections::HashMap::new();
+        for p in doc.select(&p_sel) {
+            let Some(parent) = p.parent() else { continue };
+            let text = para_text(p);
+            let entry = by_parent.entry(parent.id()).or_default();
+            entry.0 += text.len();
+            entry.1.push(text);
+        }
+        by_parent.into_values().max_by_key(|(len, _)| *len).map(|(_, ps)| ps).unwrap_or_default()
+    };
+    paragraphs.into_iter().filter(|p| !p.is_empty()).collect::<Vec<_>>().join("\n\n")
+}
+
+/// Autocomplete from past queries (`source=history`), DuckDuckGo (`source=web`) or both (default),
+/// history first, deduped and capped at 10.
+pub async fn suggest(State(state): State<Arc<crate::AppState>>, Query(p): Query<std::collections::HashMap<String,String>>) -> Json<Vec<String>> {
+    const MAX_SUGGESTIONS: usize = 10;
     let q = p.get("q").cloned().unwrap_or_default();
     if q.is_empty() { return Json(vec![]); }
-    let url = format!("https://duckduckgo.com/ac/?type=list&q={}", q);
-    let client = Client::new();
-    if let Ok(resp) = client.get(&url).send().await {
-         if let Ok(json) = resp.json::<serde_json::Value>().await {
-             if let Some(arr) = json[1].as_array() {
-                 return Json(arr.iter().filter_map(|v| v.as_str().map(String::from)).collect());
-             }
-         }
-    }
-    Json(vec![])
-}
\ No newline at end of file
+    let source = p.get("source").map(String::as_str).unwrap_or("both");
+
+    let mut out: Vec<String> = Vec::new();
+    if source == "history" || source == "both" {
+        out.extend(state.db.recent_queries_matching(&q, MAX_SUGGESTIONS).unwrap_or_default());
+    }
+    if source == "web" || source == "both" {
+        let url = format!("https://duckduckgo.com/ac/?type=list&q={}", urlencoding::encode(&q));
+        if let Ok(resp) = state.http.get(&url).send().await {
+            if let Ok(json) = resp.json::<serde_json::Value>().await {
+                if let Some(arr) = json[1].as_array() {
+                    out.extend(arr.iter().filter_map(|v| v.as_str().map(String::from)));
+                }
+            }
+        }
+    }
+
+    let mut seen = HashSet::new();
+    out.retain(|s| seen.insert(s.to_lowercase()));
+    out.truncate(MAX_SUGGESTIONS);
+    Json(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn generic(headers: &str) -> ProviderConfig {
+        ProviderConfig {
+            id: 1,
+            name: "Custom".into(),
+            type_: "generic".into(),
+            api_url: Some("http://127.0.0.1:9/?q={q}".into()),
+            api_headers: Some(headers.into()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn redacted_listing_never_contains_secrets() {
+        let config = generic(r#"{"Authorization":"Bearer sk-live-123","X-Api-Key":"abc456","X-Session-Token":"tok789","Accept":"application/json"}"#);
+        let listed = serde_json::to_string(&config.redacted()).unwrap();
+        for secret in ["sk-live-123", "abc456", "tok789"] {
+            assert!(!listed.contains(secret), "{} leaked in {}", secret, listed);
+        }
+        assert!(listed.contains("application/json"), "non-secret header should survive: {}", listed);
+        assert!(listed.contains(r#""redacted":true"#));
+    }
+
+    #[test]
+    fn unparseable_headers_are_hidden_entirely() {
+        let listed = generic("Authorization: Bearer sk-live-123").redacted();
+        assert_eq!(listed.api_headers.as_deref(), Some("***"));
+    }
+
+    #[test]
+    fn masked_headers_are_detected() {
+        assert!(has_masked_headers("***"));
+        assert!(has_masked_headers(r#"{"Authorization":"Bearer ***"}"#));
+        assert!(!has_masked_headers(r#"{"Authorization":"Bearer sk-live-123"}"#));
+    }
+
+    #[tokio::test]
+    async fn redacted_config_is_not_used_to_search() {
+        let provider = GenericApiProvider::new(generic(r#"{"Authorization":"Bearer sk-live-123"}"#).redacted());
+        let err = provider.search(Client::new(), "q".into(), SearchOptions::default()).await.unwrap_err();
+        assert!(err.to_string().contains("redacted"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn deep_read_skips_local_hosts() {
+        let page = format!("<html><body><article><p>{}</p></article></body></html>", "internal secret ".repeat(50));
+        let addr = crate::testutil::serve(axum::Router::new().route("/", axum::routing::get(move || async move { axum::response::Html(page) }))).await;
+        let mut results = vec![SearchResult { title: "t".into(), url: format!("http://{}/", addr), content: "snippet".into(), ..Default::default() }];
+        deep_read(&mut results).await;
+        assert_eq!(results[0].content, "snippet");
+    }
+
+    #[tokio::test]
+    async fn page_reads_stop_at_the_byte_cap() {
+        let addr = crate::testutil::serve(axum::Router::new().route("/", axum::routing::get(|| async { "x".repeat(1_000_000) }))).await;
+        let resp = Client::new().get(format!("http://{}/", addr)).send().await.unwrap();
+        assert_eq!(read_capped(resp, 4096).await.unwrap().len(), 4096);
+    }
+
+    /// One conversation with a newer user message and an older assistant answer, both matching "rust".
+    fn chat_db(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("bplus-{}-{}.db", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let conn = Connection::open(&path).unwrap();
+        conn.execute_batch("
+            CREATE TABLE conversations (id INTEGER PRIMARY KEY, title TEXT);
+            CREATE TABLE messages (id INTEGER PRIMARY KEY, conversation_id INTEGER, role TEXT, content TEXT, created_at TEXT);
+            INSERT INTO conversations VALUES (1, 'Chat');
+            INSERT INTO messages VALUES (1, 1, 'assistant', 'rust answer', '2024-01-01 10:00:00');
+            INSERT INTO messages VALUES (2, 1, 'user', 'more rust please', '2024-01-02 10:00:00');
+        ").unwrap();
+        path
+    }
+
+    #[tokio::test]
+    async fn local_db_prefers_assistant_hits_when_asked() {
+        let path = chat_db("prefer");
+        let newest = local_db_search("rust".into(), Some(vec![path.clone()]), None, false).await.unwrap();
+        assert!(newest[0].url.ends_with("/2"), "{}", newest[0].url);
+        let preferred = local_db_search("rust".into(), Some(vec![path.clone()]), None, true).await.unwrap();
+        assert!(preferred[0].url.ends_with("/1"), "{}", preferred[0].url);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[tokio::test]
+    async fn local_db_role_filter_drops_other_roles() {
+        let path = chat_db("role");
+        let users = local_db_search("answer".into(), Some(vec![path.clone()]), Some("user".into()), false).await.unwrap();
+        assert!(users.is_empty());
+        let assistants = local_db_search("answer".into(), Some(vec![path.clone()]), Some("assistant".into()), false).await.unwrap();
+        assert_eq!(assistants.len(), 1);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn parse_date_reads_epochs_and_years() {
+        let ymd = |raw: &str| parse_date(raw).map(|d| d.format("%Y-%m-%d").to_string());
+        assert_eq!(ymd("1700000000").as_deref(), Some("2023-11-14"));
+        assert_eq!(ymd("1700000000000").as_deref(), Some("2023-11-14"));
+        assert_eq!(ymd("2021").as_deref(), Some("2021-01-01"));
+        assert_eq!(ymd("\"2021\"").as_deref(), Some("2021-01-01"));
+        assert_eq!(ymd("42"), None);
+        assert_eq!(ymd("123456"), None);
+        assert_eq!(ymd("2024-03-05").as_deref(), Some("2024-03-05"));
+    }
+
+    #[test]
+    fn stackexchange_bodies_become_plain_text_snippets() {
+        let json: serde_json::Value = serde_json::from_str(include_str!("../testdata/stackexchange-search.json")).unwrap();
+        let results = stackexchange_results(&json);
+        assert_eq!(results.len(), 3);
+
+        let first = &results[0];
+        assert_eq!(first.title, "Why can I not borrow twice?");
+        assert_eq!(first.url, "https://stackoverflow.com/questions/31000/why-cant-i-borrow-twice");
+        assert_eq!(first.date.as_deref(), Some("2023-11-14T22:13:20Z"));
+        let (meta, body) = first.content.split_once('\n').unwrap();
+        assert_eq!(meta, "Score: 42 | Answers: 3 | Tags: rust, borrow-checker");
+        assert!(body.starts_with("I have a Vec<String> and try to take two mutable references: let a = &mut v;"), "{}", body);
+        assert!(!body.contains("<code>") && !body.contains("&amp;"), "{}", body);
+
+        let long = &results[1].content;
+        assert!(long.starts_with("Score: -1 | Answers: 0 | Tags: \n"), "{}", long);
+        assert!(long.ends_with('…'));
+        assert_eq!(long.split_once('\n').unwrap().1.chars().count(), 301);
+
+        // Fields the filter left out must not print as "null"
+        assert_eq!(results[2].content, "Score: 0 | Answers: 0 | Tags: rust\nShort.");
+    }
+
+    #[test]
+    fn content_falls_back_when_the_primary_path_is_empty() {
+        let provider = GenericApiProvider::new(ProviderConfig {
+            content_path: Some("snippet".into()),
+            content_fallbacks: Some("summary, description.text".into()),
+            ..Default::default()
+        });
+        let item = serde_json::json!({"snippet": "", "description": {"text": "from the fallback"}});
+        assert_eq!(provider.extract_content(&item), "from the fallback");
+        let item = serde_json::json!({"snippet": "primary", "summary": "unused"});
+        assert_eq!(provider.extract_content(&item), "primary");
+        assert_eq!(provider.extract_content(&serde_json::json!({})), "");
+    }
+
+    #[test]
+    fn truncate_snippet_cuts_at_a_word_boundary() {
+        assert_eq!(truncate_snippet("short enough", 20), "short enough");
+        assert_eq!(truncate_snippet("the quick brown fox", 12), "the quick…");
+        assert_eq!(truncate_snippet("unbreakableword", 5), "unbre…");
+        assert_eq!(truncate_snippet("héllo wörld ünïcode", 13), "héllo wörld…");
+    }
+
+    #[tokio::test]
+    async fn generic_provider_reads_map_shaped_results() {
+        let body = serde_json::json!({"data": {"items": {
+            "a1": {"name": "First", "link": "https://example.com/1", "text": "one"},
+            "b2": {"name": "Second", "link": "https://example.com/2", "text": "two"},
+        }}});
+        let addr = crate::testutil::serve(axum::Router::new().route("/", axum::routing::get(move || async move { axum::Json(body) }))).await;
+        let provider = GenericApiProvider::new(ProviderConfig {
+            name: "Mapped".into(),
+            type_: "generic".into(),
+            api_url: Some(format!("http://{}/?q={{q}}", addr)),
+            result_path: Some("data.items".into()),
+            title_path: Some("name".into()),
+            url_path: Some("link".into()),
+            content_path: Some("text".into()),
+            ..Default::default()
+        });
+        let mut results = provider.search(Client::new(), "q".into(), SearchOptions::default()).await.unwrap();
+        results.sort_by(|a, b| a.url.cmp(&b.url));
+        let got: Vec<_> = results.iter().map(|r| (r.title.as_str(), r.url.as_str(), r.content.as_str())).collect();
+        assert_eq!(got, [("First", "https://example.com/1", "one"), ("Second", "https://example.com/2", "two")]);
+    }
+
+    #[tokio::test]
+    async fn providers_share_the_concurrency_limit() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        let (in_flight, peak) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+        let (f, p) = (in_flight.clone(), peak.clone());
+        let addr = crate::testutil::serve(axum::Router::new().route("/", axum::routing::get(move || {
+            let (f, p) = (f.clone(), p.clone());
+            async move {
+                p.fetch_max(f.fetch_add(1, Ordering::SeqCst) + 1, Ordering::SeqCst);
+                tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+                f.fetch_sub(1, Ordering::SeqCst);
+                axum::Json(serde_json::json!([]))
+            }
+        }))).await;
+        let providers: Vec<ProviderConfig> = (0..6).map(|i| ProviderConfig {
+            id: i,
+            name: format!("P{}", i),
+            type_: "generic".into(),
+            api_url: Some(format!("http://{}/?q={{q}}", addr)),
+            url_path: Some("u".into()),
+            is_enabled: true,
+            ..Default::default()
+        }).collect();
+
+        let slots = tokio::sync::Semaphore::new(2);
+        let outcome = perform_search(Client::new(), &crate::net::RateLimiter::from_env(), &slots, providers, "q".into(), SearchOptions::default(), None).await;
+        assert_eq!(outcome.providers.len(), 6);
+        assert!(outcome.providers.iter().all(|p| p.error.is_none()));
+        assert_eq!(peak.load(Ordering::SeqCst), 2);
+    }
+
+    /// Generic provider config for a local test server answering with `[{t, u, c, d}]`.
+    fn local_provider(addr: std::net::SocketAddr, name: &str) -> ProviderConfig {
+        ProviderConfig {
+            name: name.into(),
+            type_: "generic".into(),
+            api_url: Some(format!("http://{}/?q={{q}}", addr)),
+            title_path: Some("t".into()),
+            url_path: Some("u".into()),
+            content_path: Some("c".into()),
+            date_path: Some("d".into()),
+            is_enabled: true,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn newer_results_rank_first_within_a_timeframe() {
+        let day = |n: i64| (chrono::Utc::now() - chrono::Duration::days(n)).to_rfc3339();
+        let body = serde_json::json!([
+            {"t": "rust release", "u": "https://example.com/old", "c": "", "d": day(5)},
+            {"t": "rust release", "u": "https://example.com/new", "c": "", "d": day(1)},
+        ]);
+        let addr = crate::testutil::serve(axum::Router::new().route("/", axum::routing::get(move || async move { axum::Json(body) }))).await;
+        let opts = SearchOptions { timeframe: Some("week".into()), ..Default::default() };
+        let outcome = perform_search(Client::new(), &crate::net::RateLimiter::from_env(), &search_slots(), vec![local_provider(addr, "Dated")], "rust".into(), opts, None).await;
+        let urls: Vec<_> = outcome.results.iter().map(|r| r.url.as_str()).collect();
+        assert_eq!(urls, ["https://example.com/new", "https://example.com/old"]);
+    }
+
+    #[tokio::test]
+    async fn higher_priority_provider_wins_ties() {
+        let hit = |u: &'static str| axum::routing::get(move || async move { axum::Json(serde_json::json!([{"t": "rust", "u": u, "c": ""}])) });
+        let addr = crate::testutil::serve(axum::Router::new()
+            .route("/low", hit("https://example.com/low"))
+            .route("/high", hit("https://example.com/high"))).await;
+        let provider = |path: &str, priority: i64| ProviderConfig {
+            api_url: Some(format!("http://{}/{}?q={{q}}", addr, path)),
+            priority,
+            ..local_provider(addr, path)
+        };
+        let providers = vec![provider("low", 0), provider("high", 5)];
+        let outcome = perform_search(Client::new(), &crate::net::RateLimiter::from_env(), &search_slots(), providers, "rust".into(), SearchOptions::default(), None).await;
+        let urls: Vec<_> = outcome.results.iter().map(|r| r.url.as_str()).collect();
+        assert_eq!(urls, ["https://example.com/high", "https://example.com/low"]);
+        assert_eq!(outcome.providers[0].name, "high");
+    }
+
+    #[test]
+    fn wikipedia_host_follows_lang() {
+        assert_eq!(wikipedia_host(None), "en.wikipedia.org");
+        assert_eq!(wikipedia_host(Some("de")), "de.wikipedia.org");
+        assert_eq!(wikipedia_host(Some("pt-BR")), "pt.wikipedia.org");
+        // Anything that could leave the wikipedia.org domain is dropped before it gets here
+        let opts = SearchOptions { lang: Some("evil.com/x".into()), ..Default::default() }.sanitized();
+        assert_eq!(wikipedia_host(opts.lang.as_deref()), "en.wikipedia.org");
+    }
+
+    #[test]
+    fn include_and_exclude_domain_lists() {
+        let opts = SearchOptions {
+            include_domains: vec!["*.rust-lang.org".into(), " GitHub.com ".into()],
+            exclude_domains: vec!["blog.rust-lang.org".into()],
+            ..Default::default()
+        }.sanitized();
+        assert!(opts.allows_url("https://doc.rust-lang.org/book/"));
+        assert!(opts.allows_url("https://rust-lang.org/"));
+        assert!(opts.allows_url("https://github.com/rust-lang/rust"));
+        assert!(!opts.allows_url("https://blog.rust-lang.org/2024/"));
+        assert!(!opts.allows_url("https://notrust-lang.org/"));
+        assert!(!opts.allows_url("local://research.db/notes/x"));
+
+        let exclude_only = SearchOptions { exclude_domains: vec!["pinterest.com".into()], ..Default::default() }.sanitized();
+        assert!(!exclude_only.allows_url("https://www.pinterest.com/pin/1"));
+        assert!(exclude_only.allows_url("https://example.com/"));
+        assert!(exclude_only.allows_url("local://research.db/notes/x"));
+    }
+
+    #[tokio::test]
+    async fn scoped_local_search_reads_only_the_active_file() {
+        let (active, other) = (chat_db("scope-active"), chat_db("scope-other"));
+        let name = |p: &PathBuf| p.file_name().unwrap().to_string_lossy().to_string();
+
+        let scoped = local_db_search("rust".into(), Some(vec![active.clone()]), None, false).await.unwrap();
+        assert!(!scoped.is_empty());
+        assert!(scoped.iter().all(|r| r.url.contains(&name(&active))), "{:?}", scoped);
+
+        let both = local_db_search("rust".into(), Some(vec![active.clone(), other.clone()]), None, false).await.unwrap();
+        assert!(both.iter().any(|r| r.url.contains(&name(&other))));
+        assert!(local_db_search("rust".into(), Some(vec![]), None, false).await.unwrap().is_empty());
+        let _ = std::fs::remove_file(active);
+        let _ = std::fs::remove_file(other);
+    }
+}
@@ -0,0 +1,40 @@
+//! Helpers shared by the unit tests: throwaway local servers standing in for remote APIs.
+
+use std::net::SocketAddr;
+
+/// Serves `app` on an ephemeral localhost port for the rest of the test run.
+pub async fn serve(app: axum::Router) -> SocketAddr {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+    addr
+}
+
+/// HTTPS server with a self-signed certificate that answers every request with a
+/// `302` to `location`. Clients must accept invalid certificates to talk to it.
+pub fn serve_tls_redirect(location: String) -> SocketAddr {
+    use std::io::{Read, Write};
+    let identity = native_tls::Identity::from_pkcs8(
+        include_bytes!("../testdata/localhost-cert.pem"),
+        include_bytes!("../testdata/localhost-key.pem"),
+    ).unwrap();
+    let acceptor = native_tls::TlsAcceptor::new(identity).unwrap();
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    std::thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let Ok(mut tls) = acceptor.accept(stream) else { continue };
+            let mut request = Vec::new();
+            let mut buf = [0u8; 1024];
+            while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                match tls.read(&mut buf) {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => request.extend_from_slice(&buf[..n]),
+                }
+            }
+            let _ = write!(tls, "HTTP/1.1 302 Found\r\nLocation: {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", location);
+            let _ = tls.shutdown();
+        }
+    });
+    addr
+}
@@ -0,0 +1,228 @@
+//! Tiny, side-effect free expression language for post-processing generic provider results.
+//!
+//! A transform is a `;`-separated list of assignments to `title`, `url` or `content`:
+//!
+//! ```text
+//! title = concat(title, " - ", artistName); url = prefix(url, "https://example.com")
+//! ```
+//!
+//! Expressions are string literals (`"..."`, `\"` and `\\` escapes), identifiers or calls.
+//! `title`, `url` and `content` refer to the already extracted values, any other
+//! identifier is a dotted path into the raw JSON item (same syntax as the `*_path` fields).
+//!
+//! Supported operations:
+//! - `concat(a, b, ...)`: joins every argument
+//! - `prefix(value, p)` / `suffix(value, s)`: prepends / appends when `value` is non-empty
+//! - `replace(value, from, to)`: replaces every occurrence of `from`
+//! - `default(value, fallback)`: `fallback` when `value` is empty
+//! - `upper(value)`, `lower(value)`, `trim(value)`
+
+use anyhow::{anyhow, bail, Result};
+use std::collections::HashMap;
+
+#[derive(Debug, Clone)]
+enum Expr {
+    Literal(String),
+    Field(String),
+    Call(String, Vec<Expr>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Str(String),
+    LParen,
+    RParen,
+    Comma,
+    Eq,
+    Semi,
+}
+
+fn tokenize(src: &str) -> Result<Vec<Token>> {
+    let mut out = Vec::new();
+    let mut chars = src.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        match c {
+            c if c.is_whitespace() => { chars.next(); },
+            '(' => { chars.next(); out.push(Token::LParen); },
+            ')' => { chars.next(); out.push(Token::RParen); },
+            ',' => { chars.next(); out.push(Token::Comma); },
+            '=' => { chars.next(); out.push(Token::Eq); },
+            ';' => { chars.next(); out.push(Token::Semi); },
+            '"' => {
+                chars.next();
+                let mut s = String::new();
+                loop {
+                    match chars.next() {
+                        Some('\\') => match chars.next() {
+                            Some(e) => s.push(e),
+                            None => bail!("unterminated string"),
+                        },
+                        Some('"') => break,
+                        Some(ch) => s.push(ch),
+                        None => bail!("unterminated string"),
+                    }
+                }
+                out.push(Token::Str(s));
+            },
+            c if c.is_alphanumeric() || c == '_' || c == '.' || c == '-' => {
+                let mut s = String::new();
+                while let Some(&ch) = chars.peek() {
+                    if ch.is_alphanumeric() || ch == '_' || ch == '.' || ch == '-' { s.push(ch); chars.next(); } else { break; }
+                }
+                out.push(Token::Ident(s));
+            },
+            other => bail!("unexpected character '{}'", other),
+        }
+    }
+    Ok(out)
+}
+
+struct Parser { tokens: Vec<Token>, pos: usize }
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> { self.tokens.get(self.pos) }
+
+    fn next(&mut self) -> Option<Token> {
+        let t = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        t
+    }
+
+    fn expect(&mut self, t: Token) -> Result<()> {
+        match self.next() {
+            Some(ref got) if *got == t => Ok(()),
+            got => Err(anyhow!("expected {:?}, found {:?}", t, got)),
+        }
+    }
+
+    fn expr(&mut self) -> Result<Expr> {
+        match self.next() {
+            Some(Token::Str(s)) => Ok(Expr::Literal(s)),
+            Some(Token::Ident(name)) => {
+                if self.peek() != Some(&Token::LParen) { return Ok(Expr::Field(name)); }
+                self.next();
+                let mut args = Vec::new();
+                if self.peek() != Some(&Token::RParen) {
+                    loop {
+                        args.push(self.expr()?);
+                        if self.peek() == Some(&Token::Comma) { self.next(); } else { break; }
+                    }
+                }
+                self.expect(Token::RParen)?;
+                Ok(Expr::Call(name, args))
+            },
+            t => Err(anyhow!("unexpected token {:?}", t)),
+        }
+    }
+}
+
+/// A parsed transform, validated up front so bad configs fail before any search runs.
+#[derive(Debug, Clone)]
+pub struct Transform {
+    assignments: Vec<(String, Expr)>,
+}
+
+impl Transform {
+    pub fn parse(src: &str) -> Result<Self> {
+        let mut p = Parser { tokens: tokenize(src)?, pos: 0 };
+        let mut assignments = Vec::new();
+        while p.peek().is_some() {
+            if p.peek() == Some(&Token::Semi) { p.next(); continue; }
+            let target = match p.next() {
+                Some(Token::Ident(t)) if ["title", "url", "content"].contains(&t.as_str()) => t,
+                t => bail!("can only assign to title, url or content, found {:?}", t),
+            };
+            p.expect(Token::Eq)?;
+            let expr = p.expr()?;
+            validate(&expr)?;
+            assignments.push((target, expr));
+        }
+        Ok(Self { assignments })
+    }
+
+    /// Applies the assignments in order. `lookup` resolves non-builtin identifiers against the raw item.
+    pub fn apply(&self, fields: &mut HashMap<&'static str, String>, lookup: &dyn Fn(&str) -> String) {
+        for (target, expr) in &self.assignments {
+            let value = eval(expr, fields, lookup);
+            let key = match target.as_str() { "title" => "title", "url" => "url", _ => "content" };
+            fields.insert(key, value);
+        }
+    }
+}
+
+fn validate(expr: &Expr) -> Result<()> {
+    if let Expr::Call(name, args) = expr {
+        let arity_ok = match name.as_str() {
+            "concat" => true,
+            "upper" | "lower" | "trim" => args.len() == 1,
+            "prefix" | "suffix" | "default" => args.len() == 2,
+            "replace" => args.len() == 3,
+            _ => bail!("unknown transform operation '{}'", name),
+        };
+        if !arity_ok { bail!("wrong number of arguments for '{}'", name); }
+        for a in args { validate(a)?; }
+    }
+    Ok(())
+}
+
+fn eval(expr: &Expr, fields: &HashMap<&'static str, String>, lookup: &dyn Fn(&str) -> String) -> String {
+    match expr {
+        Expr::Literal(s) => s.clone(),
+        Expr::Field(name) => match fields.get(name.as_str()) {
+            Some(v) => v.clone(),
+            None => lookup(name),
+        },
+        Expr::Call(name, args) => {
+            let vals: Vec<String> = args.iter().map(|a| eval(a, fields, lookup)).collect();
+            match name.as_str() {
+                "concat" => vals.concat(),
+                "prefix" => if vals[0].is_empty() { String::new() } else { format!("{}{}", vals[1], vals[0]) },
+                "suffix" => if vals[0].is_empty() { String::new() } else { format!("{}{}", vals[0], vals[1]) },
+                "replace" => vals[0].replace(&vals[1], &vals[2]),
+                "default" => if vals[0].is_empty() { vals[1].clone() } else { vals[0].clone() },
+                "upper" => vals[0].to_uppercase(),
+                "lower" => vals[0].to_lowercase(),
+                _ => vals[0].trim().to_string(),
+            }
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(src: &str, item: serde_json::Value, fields: &[(&'static str, &str)]) -> HashMap<&'static str, String> {
+        let mut fields: HashMap<&'static str, String> = fields.iter().map(|(k, v)| (*k, v.to_string())).collect();
+        let lookup = |path: &str| path.split('.').fold(&item, |v, key| &v[key]).as_str().unwrap_or_default().to_string();
+        Transform::parse(src).unwrap().apply(&mut fields, &lookup);
+        fields
+    }
+
+    #[test]
+    fn concat_joins_fields_literals_and_item_paths() {
+        let out = run(
+            r#"title = concat(title, " - ", artist.name)"#,
+            serde_json::json!({"artist": {"name": "Nina Simone"}}),
+            &[("title", "Feeling Good")],
+        );
+        assert_eq!(out["title"], "Feeling Good - Nina Simone");
+    }
+
+    #[test]
+    fn prefix_applies_only_to_non_empty_fields() {
+        let src = r#"url = prefix(url, "https://example.com"); content = prefix(content, "Summary: ")"#;
+        let out = run(src, serde_json::json!({}), &[("url", "/items/7"), ("content", "")]);
+        assert_eq!(out["url"], "https://example.com/items/7");
+        assert_eq!(out["content"], "");
+    }
+
+    #[test]
+    fn bad_transforms_are_rejected_when_parsed() {
+        assert!(Transform::parse(r#"author = "x""#).is_err());
+        assert!(Transform::parse("title = shout(title)").is_err());
+        assert!(Transform::parse("title = prefix(title)").is_err());
+        assert!(Transform::parse(r#"title = "unterminated"#).is_err());
+    }
+}